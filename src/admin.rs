@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+};
+
+use axum::{
+    extract::{Path, Query},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use crate::{args, control, db::DB, health, yande::DB_HANDLE};
+
+/// 历史记录默认返回的时间范围
+const DEFAULT_HISTORY_SECS: u64 = 60 * 60 * 24;
+
+#[derive(clap::Args, Debug)]
+pub struct AdminArgs {
+    /// 管理接口的监听地址
+    #[arg(long, env = "ADMIN_ADDR", default_value = "127.0.0.1:8081")]
+    pub admin_addr: SocketAddr,
+
+    /// 管理接口的 Bearer 令牌，监听非本机地址时必须设置
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+impl AdminArgs {
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if !self.admin_addr.ip().is_loopback() && self.admin_token.is_none() {
+            return Err(clap::Error::raw(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "ADMIN_TOKEN is required when ADMIN_ADDR is not loopback\n",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 等待管理员确认的表情验证
+#[derive(Debug, Clone, serde::Serialize)]
+#[cfg_attr(not(feature = "matrix"), allow(dead_code))]
+pub struct Verification {
+    pub id: String,
+    pub user: String,
+    pub device: String,
+    pub emoji: String,
+}
+
+type Pending = HashMap<String, (Verification, oneshot::Sender<bool>)>;
+
+static VERIFICATIONS: OnceLock<Mutex<Pending>> = OnceLock::new();
+
+fn verifications() -> &'static Mutex<Pending> {
+    VERIFICATIONS.get_or_init(Default::default)
+}
+
+/// 登记验证，管理员通过或拒绝后返回结果
+#[cfg_attr(not(feature = "matrix"), allow(dead_code))]
+pub fn add_verification(verification: Verification) -> oneshot::Receiver<bool> {
+    let (tx, rx) = oneshot::channel();
+    verifications()
+        .lock()
+        .unwrap()
+        .insert(verification.id.clone(), (verification, tx));
+    rx
+}
+
+/// 超时或被对方取消时移除
+#[cfg_attr(not(feature = "matrix"), allow(dead_code))]
+pub fn remove_verification(id: &str) {
+    verifications().lock().unwrap().remove(id);
+}
+
+type ApiResult = Result<Json<Value>, (StatusCode, String)>;
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn db() -> &'static DB {
+    DB_HANDLE.get_or_init(DB::init)
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/scan", post(scan))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/queue", get(queue))
+        .route("/history", get(history))
+        .route("/filters", get(filters).put(update_filters))
+        .route("/verifications", get(list_verifications))
+        .route("/verifications/:id/approve", post(approve))
+        .route("/verifications/:id/reject", post(reject))
+        .layer(middleware::from_fn(authorize))
+        // 健康检查不需要令牌
+        .merge(health::router())
+}
+
+/// 设置了令牌时所有接口都需要 `Authorization: Bearer <令牌>`
+async fn authorize<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    if let Some(token) = &args().admin.admin_token {
+        let authorized = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token);
+        if !authorized {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+async fn status() -> ApiResult {
+    let delivered = db()
+        .recent(control::now().saturating_sub(DEFAULT_HISTORY_SECS))
+        .map_err(internal_error)?
+        .len();
+    Ok(Json(json!({
+        "paused": control::is_paused(),
+        "scanning": control::is_scanning(),
+        "last_scan": control::last_scan(),
+        "threshold": control::threshold(),
+        "blacklist": control::blacklist().len(),
+        "queue": control::queue().len(),
+        "delivered_24h": delivered,
+        "verifications": verifications().lock().unwrap().len(),
+    })))
+}
+
+async fn scan() -> ApiResult {
+    control::request_scan();
+    Ok(Json(json!({ "scanning": true })))
+}
+
+async fn pause() -> ApiResult {
+    control::set_paused(true).map_err(internal_error)?;
+    Ok(Json(json!({ "paused": true })))
+}
+
+async fn resume() -> ApiResult {
+    control::set_paused(false).map_err(internal_error)?;
+    Ok(Json(json!({ "paused": false })))
+}
+
+async fn queue() -> ApiResult {
+    Ok(Json(json!(control::queue())))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HistoryQuery {
+    /// Unix 时间戳，默认为 24 小时前
+    since: Option<u64>,
+}
+
+async fn history(Query(query): Query<HistoryQuery>) -> ApiResult {
+    let since = query
+        .since
+        .unwrap_or_else(|| control::now().saturating_sub(DEFAULT_HISTORY_SECS));
+    let history = db().recent(since).map_err(internal_error)?;
+    Ok(Json(json!(history)))
+}
+
+async fn filters() -> ApiResult {
+    Ok(Json(json!({
+        "threshold": control::threshold(),
+        "blacklist": control::blacklist(),
+        "fixed_blacklist": args().control.blacklist,
+    })))
+}
+
+/// 只修改请求中出现的字段
+#[derive(Debug, serde::Deserialize)]
+struct FiltersUpdate {
+    threshold: Option<u64>,
+    blacklist: Option<Vec<String>>,
+}
+
+async fn update_filters(Json(update): Json<FiltersUpdate>) -> ApiResult {
+    if let Some(threshold) = update.threshold {
+        control::set_threshold(threshold).map_err(internal_error)?;
+    }
+    if let Some(blacklist) = update.blacklist {
+        control::set_blacklist(&blacklist).map_err(internal_error)?;
+    }
+    filters().await
+}
+
+async fn list_verifications() -> ApiResult {
+    let list: Vec<Verification> = verifications()
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(verification, _)| verification.clone())
+        .collect();
+    Ok(Json(json!(list)))
+}
+
+async fn approve(Path(id): Path<String>) -> ApiResult {
+    resolve_verification(&id, true)
+}
+
+async fn reject(Path(id): Path<String>) -> ApiResult {
+    resolve_verification(&id, false)
+}
+
+fn resolve_verification(id: &str, approved: bool) -> ApiResult {
+    let (_, tx) = verifications().lock().unwrap().remove(id).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("verification {id} not found"),
+    ))?;
+    // 等待的一方已经超时退出时忽略
+    let _ = tx.send(approved);
+    Ok(Json(json!({ "approved": approved })))
+}
+
+pub async fn serve() {
+    let addr = args().admin.admin_addr;
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(builder) => builder,
+        Err(e) => {
+            log::error!("admin api bind {} failed: {}", addr, e);
+            return;
+        }
+    };
+
+    log::info!("admin api listening on {}", addr);
+    let server = server
+        .serve(router().into_make_service())
+        .with_graceful_shutdown(async move {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to install CTRL+C handler")
+        });
+
+    server
+        .await
+        .unwrap_or_else(|e| log::error!("admin api failed: {}", e));
+}
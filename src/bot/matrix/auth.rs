@@ -0,0 +1,172 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use matrix_sdk::{
+    ruma::{OwnedDeviceId, OwnedUserId, UserId},
+    Client, Session,
+};
+use url::Url;
+
+use crate::args;
+
+/// 应用服务没有真实设备，使用固定的设备 ID
+const APPSERVICE_DEVICE_ID: &str = "YANDE_POPULAR";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// 用户名和密码
+    Password,
+    /// 预先签发的 access token 和设备 ID
+    Token,
+    /// 浏览器中完成 SSO/OIDC 登录，通过本地回调取得令牌
+    Sso,
+    /// 以应用服务注册文件中的 as_token 登录
+    Appservice,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct AuthArgs {
+    /// 登录方式
+    #[arg(long, env = "AUTH_METHOD", value_enum, default_value = "password")]
+    pub auth_method: Method,
+
+    /// AUTH_METHOD=token 时使用的 access token
+    #[arg(long, env = "ACCESS_TOKEN", hide_env_values = true)]
+    pub access_token: Option<String>,
+
+    /// access token 对应的设备 ID
+    #[arg(long, env = "DEVICE_ID")]
+    pub device_id: Option<String>,
+
+    /// SSO 回调监听的本地地址，例如 http://127.0.0.1:38080
+    /// 默认随机端口
+    #[arg(long, env = "SSO_CALLBACK_URL")]
+    pub sso_callback_url: Option<String>,
+
+    /// 应用服务的注册文件（YAML），需要同时配置到 Home Server
+    #[arg(long, env = "APPSERVICE_REGISTRATION")]
+    pub appservice_registration: Option<PathBuf>,
+
+    /// 会话文件和本地数据库的加密口令，不设置则明文保存
+    #[arg(long, env = "SESSION_PASSPHRASE", hide_env_values = true)]
+    pub session_passphrase: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Registration {
+    as_token: String,
+    sender_localpart: String,
+}
+
+/// token 和应用服务每次都从配置恢复，不需要保存会话文件
+pub fn is_stateless() -> bool {
+    matches!(
+        args().matrix.auth.auth_method,
+        Method::Token | Method::Appservice
+    )
+}
+
+/// 按配置的方式登录，密码和 SSO 登录后由调用方保存会话
+pub async fn login(
+    client: &Client,
+    homeserver_url: &Url,
+    username: &str,
+    password: Option<&str>,
+) -> Result<()> {
+    let config = &args().matrix.auth;
+    match config.auth_method {
+        Method::Password => {
+            let password = password.ok_or(anyhow::anyhow!("PASSWORD is required"))?;
+            client
+                .login_username(username, password)
+                .initial_device_display_name("yande_popular_bot")
+                .send()
+                .await?;
+        }
+        Method::Token => {
+            let access_token = config
+                .access_token
+                .clone()
+                .ok_or(anyhow::anyhow!("ACCESS_TOKEN is required"))?;
+            let device_id: OwnedDeviceId = config
+                .device_id
+                .as_deref()
+                .ok_or(anyhow::anyhow!("DEVICE_ID is required"))?
+                .into();
+            client
+                .restore_login(Session {
+                    access_token,
+                    refresh_token: None,
+                    user_id: UserId::parse(username)?,
+                    device_id,
+                })
+                .await?;
+        }
+        Method::Sso => {
+            let mut builder = client
+                .login_sso(|sso_url| async move {
+                    log::info!("open this url in browser to login: {}", sso_url);
+                    Ok(())
+                })
+                .initial_device_display_name("yande_popular_bot");
+            if let Some(url) = &config.sso_callback_url {
+                builder = builder.server_url(url);
+            }
+            builder.send().await?;
+        }
+        Method::Appservice => {
+            let path = config
+                .appservice_registration
+                .as_ref()
+                .ok_or(anyhow::anyhow!("APPSERVICE_REGISTRATION is required"))?;
+            let registration: Registration = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+            let user_id = appservice_user_id(username, &registration, homeserver_url)?;
+            register_appservice_user(homeserver_url, &registration).await?;
+            client
+                .restore_login(Session {
+                    access_token: registration.as_token,
+                    refresh_token: None,
+                    user_id,
+                    device_id: APPSERVICE_DEVICE_ID.into(),
+                })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// USER 为完整的用户 ID 时直接使用，否则用注册文件的 sender_localpart 和 Home Server 的域名
+fn appservice_user_id(
+    username: &str,
+    registration: &Registration,
+    homeserver_url: &Url,
+) -> Result<OwnedUserId> {
+    if username.starts_with('@') {
+        return Ok(UserId::parse(username)?);
+    }
+    let server_name = homeserver_url
+        .host_str()
+        .ok_or(anyhow::anyhow!("invalid homeserver url"))?;
+    Ok(UserId::parse(format!(
+        "@{}:{}",
+        registration.sender_localpart, server_name
+    ))?)
+}
+
+/// 应用服务的用户第一次使用前需要注册，已经注册过时忽略错误
+async fn register_appservice_user(homeserver_url: &Url, registration: &Registration) -> Result<()> {
+    let url = homeserver_url.join("_matrix/client/v3/register")?;
+    let resp = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(&registration.as_token)
+        .json(&serde_json::json!({
+            "type": "m.login.application_service",
+            "username": registration.sender_localpart,
+        }))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        log::debug!("register appservice user: {}", resp.text().await?);
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use matrix_sdk::{
+    event_handler::RawEvent,
+    room::{Joined, Room},
+    ruma::{
+        events::room::message::{
+            MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+        },
+        EventId, OwnedEventId, RoomId, UserId,
+    },
+    Client,
+};
+use serde_json::json;
+
+use crate::{args, command::Command, route};
+
+/// 处理房间中的管理命令，回复发到以命令为根的话题中，和发送帖子一样
+pub async fn on_room_message(
+    ev: OriginalSyncRoomMessageEvent,
+    raw: RawEvent,
+    room: Room,
+    client: Client,
+) {
+    let Room::Joined(room) = room else {
+        return;
+    };
+    if client.user_id() == Some(&*ev.sender) {
+        return;
+    }
+    let MessageType::Text(text) = &ev.content.msgtype else {
+        return;
+    };
+    let Some(command) = Command::parse(&text.body) else {
+        return;
+    };
+    let root = thread_root(&raw).unwrap_or_else(|| ev.event_id.clone());
+
+    if let Ok(Command::Leave(target)) = &command {
+        leave(&room, &ev, &root, &client, target.as_deref()).await;
+        return;
+    }
+
+    let reply_text = match command {
+        Ok(command) if command.requires_admin() && !is_admin(&client, &room, &ev.sender).await => {
+            "没有权限".to_string()
+        }
+        Ok(command) => {
+            log::info!("{} run command: {:?}", ev.sender, command);
+            command
+                .execute(ev.sender.as_str())
+                .await
+                .unwrap_or_else(|e| format!("执行失败：{}", e))
+        }
+        Err(e) => e.to_string(),
+    };
+
+    reply(&room, &ev, &root, &reply_text).await;
+}
+
+/// 命令本身在话题中时回复到同一个话题，话题中的消息不能再作为新话题的根
+/// 没有启用 ruma 的话题支持，直接读取原始事件
+fn thread_root(raw: &RawEvent) -> Option<OwnedEventId> {
+    let event: serde_json::Value = serde_json::from_str(raw.get()).ok()?;
+    let relation = &event["content"]["m.relates_to"];
+    if relation["rel_type"] != "m.thread" {
+        return None;
+    }
+    EventId::parse(relation["event_id"].as_str()?).ok()
+}
+
+/// 先回复再退出，退出当前房间后就不能再发消息了
+/// 按要退出的房间检查权限，退出的房间重启后不会自动重新加入
+async fn leave(
+    room: &Joined,
+    ev: &OriginalSyncRoomMessageEvent,
+    root: &EventId,
+    client: &Client,
+    target: Option<&str>,
+) {
+    let result = async {
+        let target = match target {
+            Some(target) => {
+                let room_id = super::room::resolve(client, target).await?;
+                client
+                    .get_joined_room(&room_id)
+                    .ok_or(anyhow::anyhow!("没有加入 {}", target))?
+            }
+            None => room.clone(),
+        };
+        if !is_admin(client, &target, &ev.sender).await {
+            reply(room, ev, root, "没有权限").await;
+            return anyhow::Ok(());
+        }
+        reply(room, ev, root, &format!("正在退出 {}", target.room_id())).await;
+        log::info!("{} let bot leave {}", ev.sender, target.room_id());
+        target.leave().await?;
+        super::room::set_left(target.room_id(), true)?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        reply(room, ev, root, &format!("执行失败：{}", e)).await;
+    }
+}
+
+/// 在话题中直接回复命令消息，不是回退的引用
+async fn reply(room: &Joined, ev: &OriginalSyncRoomMessageEvent, root: &EventId, text: &str) {
+    let result = async {
+        let mut content = serde_json::to_value(RoomMessageEventContent::text_markdown(text))?;
+        content["m.relates_to"] = json!({
+            "rel_type": "m.thread",
+            "event_id": root,
+            "is_falling_back": false,
+            "m.in_reply_to": { "event_id": ev.event_id },
+        });
+        room.send_raw(content, "m.room.message", None).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("reply command failed: {}", e);
+    }
+}
+
+/// `ADMINS` 中的用户，或在配置的房间（`ROOM_ID` 和路由目标）中权限等级足够的用户
+/// 私信和用户自己建的房间中的权限等级不算
+async fn is_admin(client: &Client, room: &Joined, user: &UserId) -> bool {
+    if args().admins.iter().any(|admin| admin == user.as_str()) {
+        return true;
+    }
+    if !is_configured(client, room.room_id()).await {
+        return false;
+    }
+    match room.get_member(user).await {
+        Ok(Some(member)) => member.power_level() >= args().matrix.admin_power_level,
+        Ok(None) => false,
+        Err(e) => {
+            log::error!("get member {} failed: {}", user, e);
+            false
+        }
+    }
+}
+
+async fn is_configured(client: &Client, room_id: &RoomId) -> bool {
+    for target in std::iter::once(args().matrix.room_id()).chain(route::all_targets()) {
+        match super::room::resolve(client, target).await {
+            Ok(id) if id == room_id => return true,
+            Ok(_) => {}
+            Err(e) => log::warn!("resolve {} failed: {}", target, e),
+        }
+    }
+    false
+}
@@ -0,0 +1,79 @@
+use std::{sync::OnceLock, time::Duration};
+
+use matrix_sdk::{config::SyncSettings, Client, LoopCtrl};
+use tokio::sync::watch;
+
+const MIN_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// 最近一次同步是否成功，断线时发送的消息会等待重新连上
+static ONLINE: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn online() -> &'static watch::Sender<bool> {
+    ONLINE.get_or_init(|| watch::channel(false).0)
+}
+
+fn set_online(value: bool) {
+    online().send_if_modified(|online| std::mem::replace(online, value) != value);
+}
+
+pub fn is_online() -> bool {
+    *online().borrow()
+}
+
+/// 等待同步恢复，消息按调用顺序排队发送
+pub async fn wait_online() {
+    let mut rx = online().subscribe();
+    if !*rx.borrow() {
+        log::info!("matrix offline, waiting for reconnection");
+    }
+    // Sender 是静态的，不会被关闭
+    let _ = rx.wait_for(|online| *online).await;
+}
+
+/// 指数退避，从 1 秒开始翻倍，最多 5 分钟
+pub struct Backoff(Duration);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff(MIN_DELAY)
+    }
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        self.0 = MIN_DELAY;
+    }
+
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.0).await;
+        self.0 = (self.0 * 2).min(MAX_DELAY);
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.0
+    }
+}
+
+/// 同步循环退出后按退避时间重启，同步成功过则重新计时
+pub async fn supervise(client: Client) {
+    let mut backoff = Backoff::default();
+    loop {
+        let result = client
+            .sync_with_callback(SyncSettings::new(), |_| async {
+                set_online(true);
+                LoopCtrl::Continue
+            })
+            .await;
+
+        if is_online() {
+            backoff.reset();
+        }
+        set_online(false);
+        match result {
+            Ok(()) => log::warn!("sync stopped, restart in {:?}", backoff.delay()),
+            Err(e) => log::error!("sync failed: {}, restart in {:?}", e, backoff.delay()),
+        }
+        backoff.wait().await;
+    }
+}
@@ -1,6 +1,7 @@
+use std::time::Duration;
+
 use matrix_sdk::{
     self,
-    config::SyncSettings,
     encryption::verification::{format_emojis, SasVerification, Verification},
     ruma::{
         events::{
@@ -17,97 +18,68 @@ use matrix_sdk::{
     Client,
 };
 
-mod web_server {
-    use std::{
-        net::{SocketAddr, TcpListener},
-        sync::OnceLock,
-    };
-
-    use axum::{extract::Path, http::StatusCode, routing::get, Router};
-    use tokio::sync::broadcast;
-
-    pub static TX: OnceLock<broadcast::Sender<String>> = OnceLock::new();
-    pub static PORT: OnceLock<u16> = OnceLock::new();
+use crate::{admin, args};
 
-    pub async fn axum_verify_server() {
-        TX.get_or_init(|| {
-            let (tx, _) = broadcast::channel(64);
-            tx
-        });
-
-        let port = PORT.get_or_init(|| {
-            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-            listener.local_addr().unwrap().port()
-        });
+/// 允许的用户直接确认，其他用户需要管理员通过管理接口确认表情一致
+async fn wait_for_confirmation(client: Client, sas: SasVerification) {
+    let emoji = format_emojis(sas.emoji().expect("The emoji should be available now"));
 
-        let app: Router = Router::new().route("/verify/:code", get(verify_code));
-        let addr = SocketAddr::from(([127, 0, 0, 1], *port));
-        let server = axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .with_graceful_shutdown(async move {
-                tokio::signal::ctrl_c()
-                    .await
-                    .expect("failed to install CTRL+C handler")
-            });
+    println!("\nDo the emojis match: \n{}", emoji);
 
-        server.await.unwrap();
+    if super::keys::is_trusted(sas.other_device().user_id()) {
+        log::info!("auto confirm trusted user {}", sas.other_device().user_id());
+        confirm(&client, &sas).await;
+        return;
     }
 
-    async fn verify_code(Path(code): Path<String>) -> StatusCode {
-        let result = TX.get().unwrap().send(code);
-
-        if result.is_err() {
-            return StatusCode::INTERNAL_SERVER_ERROR;
-        }
-
-        StatusCode::OK
-    }
-}
-
-async fn wait_for_confirmation(client: Client, sas: SasVerification) {
-    let emoji = sas.emoji().expect("The emoji should be available now");
-
-    println!("\nDo the emojis match: \n{}", format_emojis(emoji));
-
-    let code = uuid::Uuid::new_v4().simple().to_string();
+    let device = sas.other_device();
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let rx = admin::add_verification(admin::Verification {
+        id: id.clone(),
+        user: device.user_id().to_string(),
+        device: device.device_id().to_string(),
+        emoji,
+    });
 
     println!("Please run the command to allow:");
     println!(
-        "local: wget  -O - http://127.0.0.1:{}/verify/{}",
-        web_server::PORT.get().unwrap(),
-        code
-    );
-
-    println!(
-        "docker: docker exec matrix_webhook wget -O - http://127.0.0.1:{}/verify/{}",
-        web_server::PORT.get().unwrap(),
-        code
+        "curl -X POST -H \"Authorization: Bearer $ADMIN_TOKEN\" http://{}/verifications/{}/approve",
+        args().admin.admin_addr,
+        id
     );
 
     // 最多等待 5 分钟
-    let timeout = std::time::Instant::now();
-    let mut rx = web_server::TX
-        .get_or_init(|| {
-            let (tx, _) = tokio::sync::broadcast::channel(64);
-            tx
-        })
-        .subscribe();
+    match tokio::time::timeout(Duration::from_secs(5 * 60), rx).await {
+        Ok(Ok(true)) => {
+            println!("Verification approved");
+            confirm(&client, &sas).await;
+        }
+        Ok(Ok(false)) | Ok(Err(_)) => {
+            log::info!("verification with {} rejected", device.user_id());
+            cancel(&sas).await;
+        }
+        Err(_) => {
+            admin::remove_verification(&id);
+            log::warn!("verification with {} timed out", device.user_id());
+            cancel(&sas).await;
+        }
+    }
+}
 
-    while timeout.elapsed().as_secs() < 5 * 60 {
-        if let Ok(msg) = rx.try_recv() {
-            log::warn!("msg: {}", msg);
-            if msg == code {
-                println!("Code matches");
-                sas.confirm().await.unwrap();
+async fn cancel(sas: &SasVerification) {
+    sas.cancel()
+        .await
+        .unwrap_or_else(|e| log::error!("cancel verification failed: {}", e));
+}
 
-                if sas.is_done() {
-                    print_result(&sas);
-                    print_devices(sas.other_device().user_id(), &client).await;
-                }
-                break;
-            }
-        }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+async fn confirm(client: &Client, sas: &SasVerification) {
+    if let Err(e) = sas.confirm().await {
+        log::error!("confirm verification failed: {}", e);
+        return;
+    }
+    if sas.is_done() {
+        print_result(sas);
+        print_devices(sas.other_device().user_id(), client).await;
     }
 }
 
@@ -141,7 +113,17 @@ async fn print_devices(user_id: &UserId, client: &Client) {
     }
 }
 
-pub async fn sync(client: Client) -> matrix_sdk::Result<()> {
+/// 注册验证和命令等事件处理，同步由 `connection::supervise` 负责
+pub fn register_handlers(client: &Client) {
+    register_verification(client);
+    client.add_event_handler(super::command::on_room_message);
+    client.add_event_handler(super::reaction::on_reaction);
+    client.add_event_handler(super::reaction::on_redaction);
+    client.add_event_handler(super::room::on_invite);
+}
+
+/// 只注册设备验证的事件处理
+pub fn register_verification(client: &Client) {
     client.add_event_handler(
         |ev: ToDeviceKeyVerificationRequestEvent, client: Client| async move {
             let request = client
@@ -263,10 +245,4 @@ pub async fn sync(client: Client) -> matrix_sdk::Result<()> {
             }
         },
     );
-
-    tokio::spawn(web_server::axum_verify_server());
-
-    client.sync(SyncSettings::new()).await?;
-
-    Ok(())
 }
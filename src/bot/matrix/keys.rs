@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use matrix_sdk::{
+    ruma::{
+        api::client::{
+            keys::get_keys,
+            uiaa::{AuthData, Password, UserIdentifier},
+        },
+        UserId,
+    },
+    Client,
+};
+
+use super::connection;
+use crate::args;
+
+/// 重新检查允许的用户的间隔
+const TRUST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(clap::Args, Debug)]
+pub struct KeysArgs {
+    /// 自动信任这些用户，逗号分隔，不需要手动比对表情
+    #[arg(long, env = "TRUSTED_USERS", value_delimiter = ',')]
+    pub trusted_users: Vec<String>,
+}
+
+pub fn is_trusted(user: &UserId) -> bool {
+    args()
+        .matrix
+        .keys
+        .trusted_users
+        .iter()
+        .any(|trusted| trusted == user.as_str())
+}
+
+/// 设置交叉签名，然后定期信任允许的用户
+pub async fn maintain(client: Client) {
+    connection::wait_online().await;
+
+    bootstrap_cross_signing(&client)
+        .await
+        .unwrap_or_else(|e| log::error!("bootstrap cross signing failed: {}", e));
+
+    let mut interval = tokio::time::interval(TRUST_INTERVAL);
+    loop {
+        interval.tick().await;
+        trust_users(&client)
+            .await
+            .unwrap_or_else(|e| log::error!("trust users failed: {}", e));
+    }
+}
+
+/// 本地已有交叉签名私钥时跳过，否则新建，需要时用密码通过交互式认证
+/// 私钥保存在 matrix-sdk 的存储中，matrix-sdk 0.6 不支持服务器上的密钥存储，
+/// 服务器上已有交叉签名身份时不会覆盖，需要在其他客户端重置
+async fn bootstrap_cross_signing(client: &Client) -> Result<()> {
+    let encryption = client.encryption();
+    let user_id = client.user_id().ok_or(anyhow::anyhow!("not logged in"))?;
+    let complete = encryption
+        .cross_signing_status()
+        .await
+        .is_some_and(|status| {
+            status.has_master && status.has_self_signing && status.has_user_signing
+        });
+
+    if !complete {
+        if has_master_key(client, user_id).await? {
+            anyhow::bail!(
+                "{} already has a cross signing identity but its private keys are not in the local store, refuse to replace it",
+                user_id
+            );
+        }
+        log::info!("bootstrap cross signing");
+        if let Err(e) = encryption.bootstrap_cross_signing(None).await {
+            let Some(response) = e.uiaa_response() else {
+                return Err(e.into());
+            };
+            let password = args().matrix.password.as_deref().ok_or(anyhow::anyhow!(
+                "PASSWORD is required to bootstrap cross signing"
+            ))?;
+            let mut auth = Password::new(
+                UserIdentifier::UserIdOrLocalpart(user_id.as_str()),
+                password,
+            );
+            auth.session = response.session.as_deref();
+            encryption
+                .bootstrap_cross_signing(Some(AuthData::Password(auth)))
+                .await?;
+        }
+    }
+
+    // 用自签名密钥签名本设备，其他用户看到的机器人就是已验证的
+    let device_id = client.device_id().ok_or(anyhow::anyhow!("not logged in"))?;
+    if let Some(device) = encryption.get_device(user_id, device_id).await? {
+        if !device.is_verified() {
+            device.verify().await?;
+            log::info!("own device {} signed", device_id);
+        }
+    }
+    Ok(())
+}
+
+/// 直接查询服务器，本地存储中的身份可能还没有更新
+async fn has_master_key(client: &Client, user_id: &UserId) -> Result<bool> {
+    let mut request = get_keys::v3::Request::new();
+    request.device_keys.insert(user_id.to_owned(), Vec::new());
+    let response = client.send(request, None).await?;
+    Ok(response.master_keys.contains_key(user_id))
+}
+
+/// 用用户签名密钥签名允许的用户，他们交叉签名过的设备都会被信任
+async fn trust_users(client: &Client) -> Result<()> {
+    for user in args().matrix.keys.trusted_users.iter() {
+        let user_id = <&UserId>::try_from(user.as_str())?;
+        match client.encryption().get_user_identity(user_id).await? {
+            Some(identity) if !identity.is_verified() => {
+                identity.verify().await?;
+                log::info!("trusted user {}", user_id);
+            }
+            Some(_) => {}
+            None => log::debug!("no cross signing identity of {}", user_id),
+        }
+    }
+    Ok(())
+}
@@ -1,22 +1,192 @@
+pub mod auth;
+mod command;
+mod connection;
 pub mod e2ee;
-use std::{fs, path::Path, sync::OnceLock};
+pub mod keys;
+mod reaction;
+mod room;
+mod session;
+pub mod spoiler;
+mod thumbnail;
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
 use anyhow::Result;
 use image::GenericImageView;
 use matrix_sdk::{
-    self, attachment::AttachmentConfig, config::SyncSettings, room::Joined,
-    ruma::events::room::message::RoomMessageEventContent, Client,
+    self,
+    config::SyncSettings,
+    room::Joined,
+    ruma::{
+        api::{
+            client::{error::ErrorKind, room::create_room},
+            error::{FromHttpResponseError, ServerError},
+        },
+        events::room::{
+            message::RoomMessageEventContent, power_levels::RoomPowerLevelsEventContent,
+            EncryptedFile, EncryptedFileInit,
+        },
+        serde::Raw,
+        EventId, Int, OwnedEventId, OwnedRoomId, UserId,
+    },
+    Client, HttpError, RumaApiError,
 };
+use matrix_sdk_crypto::AttachmentEncryptor;
 use mime_guess::mime;
-use tokio::runtime::Runtime;
+use serde_json::json;
 use url::Url;
 
-use crate::args;
+use spoiler::Policy;
+
+use crate::{args, caption::Caption, db::DB, route, yande::DB_HANDLE};
 
-pub static ROOM: OnceLock<Joined> = OnceLock::new();
 pub static CLIENT: OnceLock<Client> = OnceLock::new();
 
-async fn upload(file_path: &Path) -> Result<()> {
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Matrix")]
+pub struct MatrixArgs {
+    /// Home Server URL
+    #[arg(long, env = "HOME_SERVER_URL")]
+    pub home_server_url: Option<String>,
+
+    /// 发送到的房间ID
+    #[arg(long, env = "ROOM_ID")]
+    pub room_id: Option<String>,
+
+    /// 机器人用户名
+    #[arg(short, long, env = "USER")]
+    pub user: Option<String>,
+
+    /// 机器人密码，AUTH_METHOD=password 时使用
+    #[arg(short, long, env = "PASSWORD")]
+    pub password: Option<String>,
+
+    #[command(flatten)]
+    pub auth: auth::AuthArgs,
+
+    #[command(flatten)]
+    pub keys: keys::KeysArgs,
+
+    /// 房间内权限等级不低于该值的用户也可以使用管理命令
+    #[arg(long, env = "ADMIN_POWER_LEVEL", default_value = "50")]
+    pub admin_power_level: i64,
+
+    /// 自动接受这些用户或服务器的邀请，逗号分隔，例如 @admin:example.org,example.org
+    #[arg(long, env = "INVITE_ALLOWLIST", value_delimiter = ',')]
+    pub invite_allowlist: Vec<String>,
+
+    /// 图片缩略图长边的像素数，为 0 时不生成缩略图
+    #[arg(long, env = "THUMBNAIL_SIZE", default_value = "800")]
+    pub thumbnail_size: u32,
+
+    #[command(flatten)]
+    pub spoiler: spoiler::SpoilerArgs,
+}
+
+impl MatrixArgs {
+    /// 登录信息只在启动时读取，重新加载时保留当前的值，返回是否有修改
+    pub fn keep_login(&mut self, current: &Self) -> bool {
+        let changed = (
+            &self.home_server_url,
+            &self.user,
+            &self.password,
+            &self.auth,
+        ) != (
+            &current.home_server_url,
+            &current.user,
+            &current.password,
+            &current.auth,
+        );
+        if changed {
+            self.home_server_url = current.home_server_url.clone();
+            self.user = current.user.clone();
+            self.password = current.password.clone();
+            self.auth = current.auth.clone();
+        }
+        changed
+    }
+
+    /// 选择 Matrix 后端时这些参数必须设置，检查后可以直接取用
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        for (value, name) in [
+            (&self.home_server_url, "HOME_SERVER_URL"),
+            (&self.room_id, "ROOM_ID"),
+            (&self.user, "USER"),
+        ] {
+            if value.is_none() {
+                return Err(clap::Error::raw(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    format!("{name} is required for the matrix backend\n"),
+                ));
+            }
+        }
+        if let Err(e) = Url::parse(self.home_server_url()) {
+            return Err(clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                format!("invalid HOME_SERVER_URL: {e}\n"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn home_server_url(&self) -> &str {
+        self.home_server_url.as_deref().unwrap_or_default()
+    }
+
+    pub fn room_id(&self) -> &str {
+        self.room_id.as_deref().unwrap_or_default()
+    }
+
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or_default()
+    }
+}
+
+/// 上传文件，加密房间返回 ("file", 加密信息)，否则返回 ("url", mxc 地址)
+async fn upload_media(
+    room: &Joined,
+    mime: &mime::Mime,
+    data: &[u8],
+) -> Result<(&'static str, serde_json::Value)> {
+    let client = client()?;
+    if room.is_encrypted() {
+        let mut reader = data;
+        let mut encryptor = AttachmentEncryptor::new(&mut reader);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted)?;
+        // 加密后的内容不透露原始类型
+        let response = client
+            .media()
+            .upload(&mime::APPLICATION_OCTET_STREAM, &encrypted)
+            .await?;
+        let keys = encryptor.finish();
+        let file: EncryptedFile = EncryptedFileInit {
+            url: response.content_uri,
+            key: keys.key,
+            iv: keys.iv,
+            hashes: keys.hashes,
+            v: keys.version,
+        }
+        .into();
+        Ok(("file", serde_json::to_value(file)?))
+    } else {
+        let response = client.media().upload(mime, data).await?;
+        Ok(("url", json!(response.content_uri)))
+    }
+}
+
+/// 上传图片并发送 m.image 事件，`thread` 为 (根事件, 上一条事件)
+/// `spoiler` 为需要标记剧透的帖子分级
+async fn upload(
+    room: &Joined,
+    file_path: &Path,
+    thread: Option<(&EventId, &EventId)>,
+    spoiler: Option<&str>,
+) -> Result<OwnedEventId> {
     let file = fs::read(file_path)?;
     let filename = file_path
         .file_name()
@@ -25,99 +195,267 @@ async fn upload(file_path: &Path) -> Result<()> {
         .unwrap_or("image.jpg");
     let mime = mime_guess::from_path(file_path).first_or_octet_stream();
 
-    let config = match mime.type_() {
-        mime::IMAGE => {
-            // 从文件Bytes获取图片信息
-            let image = image::load_from_memory(&file)?;
-            let (width, height) = image.dimensions();
-            let blurhash = blurhash::encode(4, 3, width, height, image.to_rgba8().as_raw())?;
-
-            let info = matrix_sdk::attachment::BaseImageInfo {
-                height: Some(height.try_into()?),
-                width: Some(width.try_into()?),
-                size: Some(file.len().try_into()?),
-                blurhash: Some(blurhash),
-            };
-
-            AttachmentConfig::new().info(matrix_sdk::attachment::AttachmentInfo::Image(info))
+    let mut info = json!({
+        "mimetype": mime.to_string(),
+        "size": file.len(),
+    });
+    if mime.type_() == mime::IMAGE {
+        // 从文件Bytes获取图片信息
+        let image = image::load_from_memory(&file)?;
+        let (width, height) = image.dimensions();
+        let small = thumbnail::generate(&image, args().matrix.thumbnail_size);
+        // blurhash 只需要很少的像素，用缩略图计算
+        let blurhash = {
+            let source = small.as_ref().unwrap_or(&image);
+            let (w, h) = source.dimensions();
+            blurhash::encode(4, 3, w, h, source.to_rgba8().as_raw())?
+        };
+        info["w"] = json!(width);
+        info["h"] = json!(height);
+        info["xyz.amorgan.blurhash"] = json!(blurhash);
+
+        // 剧透的帖子使用模糊的缩略图，避免在时间线中直接显示
+        let small = match spoiler {
+            Some(_) => Some(spoiler::blurred_thumbnail(&blurhash, width, height)?),
+            None => small,
+        };
+        if let Some(small) = small {
+            let thumbnail = thumbnail::encode_jpeg(&small)?;
+            let (key, value) = upload_media(room, &mime::IMAGE_JPEG, &thumbnail).await?;
+            info[format!("thumbnail_{key}")] = value;
+            info["thumbnail_info"] = json!({
+                "mimetype": mime::IMAGE_JPEG.to_string(),
+                "size": thumbnail.len(),
+                "w": small.width(),
+                "h": small.height(),
+            });
         }
-        _ => AttachmentConfig::default(),
+    }
+
+    let msgtype = match mime.type_() {
+        mime::IMAGE => "m.image",
+        _ => "m.file",
     };
+    let mut content = json!({
+        "msgtype": msgtype,
+        "body": filename,
+        "info": info,
+    });
 
-    ROOM.get_or_init(room_init)
-        .send_attachment(filename, &mime, &file, config)
-        .await?;
-    Ok(())
+    let (key, value) = upload_media(room, &mime, &file).await?;
+    content[key] = value;
+    if let Some(rating) = spoiler {
+        spoiler::mark(&mut content, rating);
+    }
+
+    if let Some((root, latest)) = thread {
+        content["m.relates_to"] = json!({
+            "rel_type": "m.thread",
+            "event_id": root,
+            "is_falling_back": true,
+            "m.in_reply_to": { "event_id": latest },
+        });
+    }
+
+    let response = room.send_raw(content, "m.room.message", None).await?;
+    Ok(response.event_id)
 }
 
-pub async fn send_attachment(file_path: &Path) -> Result<()> {
-    upload(file_path).await?;
+/// 说明作为根消息，图片依次发到以它为根的话题中，各条消息的事件 ID 发出后立即加入 `messages`
+/// 可疑和限制级的帖子按房间的设置正常发送、标记剧透或跳过
+pub async fn send_gallery(
+    room_id: &str,
+    rating: &str,
+    caption: &Caption,
+    files: &[PathBuf],
+    messages: &mut Vec<String>,
+) -> Result<()> {
+    let spoiler = match spoiler::policy(room_id, rating) {
+        Policy::Normal => None,
+        Policy::Spoiler => Some(rating),
+        Policy::Skip => {
+            log::info!("skip rating {} post in {}", rating, room_id);
+            return Ok(());
+        }
+    };
+
+    connection::wait_online().await;
+    let room = &joined_room(room_id)?;
+    let root = room
+        .send(
+            RoomMessageEventContent::text_html(&caption.text, &caption.html),
+            None,
+        )
+        .await?
+        .event_id;
+
+    messages.push(root.to_string());
+    let mut latest = root.clone();
+    for file in files {
+        latest = upload(room, file, Some((&root, &latest)), spoiler).await?;
+        messages.push(latest.to_string());
+    }
     Ok(())
 }
 
-pub async fn send_msg(msg: &str) -> Result<()> {
-    let msg = RoomMessageEventContent::text_markdown(msg);
-    ROOM.get_or_init(room_init).send(msg, None).await?;
+/// 没有路由规则匹配时发送到的房间
+pub fn default_target() -> &'static str {
+    args().matrix.room_id()
+}
+
+/// 所有房间共用一个客户端，机器人需要已经加入目标房间
+fn joined_room(target: &str) -> Result<Joined> {
+    client()?
+        .get_joined_room(&room::lookup(target)?)
+        .ok_or(anyhow::anyhow!("not joined room {}", target))
+}
+
+/// 私信用户，第一次私信时创建私聊房间并邀请对方
+pub async fn send_dm(user: &str, caption: &Caption) -> Result<()> {
+    let client = client()?;
+    connection::wait_online().await;
+    let user_id = <&UserId>::try_from(user)?;
+    let db = DB_HANDLE.get_or_init(DB::init);
+    let key = format!("dm_room:{user}");
+
+    let room = match db
+        .get_value::<OwnedRoomId>(&key)?
+        .and_then(|room_id| client.get_joined_room(&room_id))
+    {
+        Some(room) => room,
+        None => {
+            let room = create_dm_room(client, user_id).await?;
+            db.set_value(&key, &room.room_id())?;
+            room
+        }
+    };
+    room.send(
+        RoomMessageEventContent::text_html(&caption.text, &caption.html),
+        None,
+    )
+    .await?;
     Ok(())
 }
 
-async fn login(homeserver_url: &str, username: &str, password: &str) -> Result<Client> {
-    let homeserver_url = Url::parse(homeserver_url).expect("Couldn't parse the homeserver URL");
+async fn create_dm_room(client: &Client, user_id: &UserId) -> Result<Joined> {
+    let invite = [user_id.to_owned()];
+    let mut request = create_room::v3::Request::new();
+    request.invite = &invite;
+    request.is_direct = true;
+    // TrustedPrivateChat 会给受邀者和创建者相同的权限，这里只有机器人可以管理房间
+    request.preset = Some(create_room::v3::RoomPreset::PrivateChat);
+    let mut power_levels = RoomPowerLevelsEventContent::new();
+    if let Some(me) = client.user_id() {
+        power_levels.users.insert(me.to_owned(), Int::from(100));
+    }
+    power_levels.users.insert(user_id.to_owned(), Int::from(0));
+    request.power_level_content_override = Some(Raw::new(&power_levels)?);
+    let room_id = client.create_room(request).await?.room_id;
+
+    // 新房间要等同步之后才能取到
+    for _ in 0..10 {
+        if let Some(room) = client.get_joined_room(&room_id) {
+            return Ok(room);
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    Err(anyhow::anyhow!("room {} not synced", room_id))
+}
 
-    let db_path = std::path::PathBuf::from(&args().data_dir).join("db");
-    let mut client = Client::builder()
-        .homeserver_url(&homeserver_url)
-        .sled_store(&db_path, None)?
+async fn build_client(homeserver_url: &Url, db_path: &Path) -> Result<Client> {
+    let client = Client::builder()
+        .homeserver_url(homeserver_url)
+        .sled_store(db_path, args().matrix.auth.session_passphrase.as_deref())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "open matrix store {} failed: {e}, stop the running service first if any",
+                db_path.display()
+            )
+        })?
         .build()
         .await
         .map_err(|e| {
             log::error!("client build error: {}", e);
             e
         })?;
+    Ok(client)
+}
+
+async fn login(homeserver_url: &str, username: &str, password: Option<&str>) -> Result<Client> {
+    let homeserver_url = Url::parse(homeserver_url)
+        .map_err(|e| anyhow::anyhow!("invalid homeserver url {homeserver_url}: {e}"))?;
+
+    let db_path = std::path::PathBuf::from(&args().data_dir).join("db");
+    let mut client = build_client(&homeserver_url, &db_path).await?;
 
     if !client.logged_in() {
         let session_file = std::path::PathBuf::from(&args().data_dir).join("session");
 
-        if session_file.exists()
-            && restore_login(&client, &session_file).await.is_ok()
-            && client.logged_in()
-            && client.sync_once(SyncSettings::new()).await.is_ok()
-        {
+        if auth::is_stateless() {
+            auth::login(&client, &homeserver_url, username, password).await?;
+        } else if session_file.exists() && restore_session(&client, &session_file).await? {
             log::info!("Restored login from session file");
         } else {
             drop(client);
-            // 清理数据库
-            fs::remove_dir_all(&db_path)?;
-            client = Client::builder()
-                .homeserver_url(&homeserver_url)
-                .sled_store(db_path, None)?
-                .build()
-                .await
-                .map_err(|e| {
-                    log::error!("client build error: {}", e);
-                    e
-                })?;
-            login_username(&client, &session_file, username, password).await?;
+            // 会话已经失效，存储中的设备不能再用，只清理 matrix-sdk 的存储
+            clear_store(&db_path)?;
+            client = build_client(&homeserver_url, &db_path).await?;
+            auth::login(&client, &homeserver_url, username, password).await?;
+            if let Some(session) = client.session() {
+                session::save(
+                    &session_file,
+                    &session,
+                    args().matrix.auth.session_passphrase.as_deref(),
+                )?;
+            }
         };
 
-        log::info!("Logged in as {}", username);
+        log::info!("Logged in as {:?}", client.user_id());
     }
 
     Ok(client)
 }
 
-async fn get_room(client: &Client, room_id: &str) -> Result<Joined> {
-    client.sync_once(SyncSettings::new()).await?;
-    let room = client
-        .get_joined_room(room_id.try_into()?)
-        .ok_or(anyhow::Error::msg("No room with that alias exists"))?;
+/// 恢复会话并同步一次，服务器返回 M_UNKNOWN_TOKEN 时返回 false 以便重新登录
+/// 网络错误等其他错误直接返回，由调用方重试，不清理存储
+async fn restore_session(client: &Client, session_file: &Path) -> Result<bool> {
+    restore_login(client, session_file).await?;
+    match client.sync_once(SyncSettings::new()).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_unknown_token(&e) => {
+            log::warn!("session expired: {}", e);
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
 
-    Ok(room)
+fn is_unknown_token(error: &matrix_sdk::Error) -> bool {
+    matches!(
+        error,
+        matrix_sdk::Error::Http(HttpError::Api(FromHttpResponseError::Server(
+            ServerError::Known(RumaApiError::ClientApi(error))
+        ))) if matches!(error.kind, ErrorKind::UnknownToken { .. })
+    )
+}
+
+/// matrix-sdk 的状态和加密存储是 `db_path` 下的子目录，应用自己的数据库也在 `db_path`，不能删除整个目录
+fn clear_store(db_path: &Path) -> Result<()> {
+    for name in ["matrix-sdk-state", "matrix-sdk-crypto"] {
+        let path = db_path.join(name);
+        if path.exists() {
+            log::warn!("removing {:?}", path);
+            fs::remove_dir_all(&path)?;
+        }
+    }
+    Ok(())
 }
 
 async fn restore_login(client: &Client, session_file: impl AsRef<Path>) -> Result<()> {
-    let session = fs::read_to_string(session_file)?;
-    let session = serde_json::from_str(&session)?;
+    let session = session::load(
+        session_file,
+        args().matrix.auth.session_passphrase.as_deref(),
+    )?;
     client.restore_login(session).await.map_err(|e| {
         log::error!("restore login error: {}", e);
         e
@@ -125,49 +463,91 @@ async fn restore_login(client: &Client, session_file: impl AsRef<Path>) -> Resul
     Ok(())
 }
 
-async fn login_username(
-    client: &Client,
-    session_file: impl AsRef<Path>,
-    username: &str,
-    password: &str,
-) -> Result<()> {
-    client
-        .login_username(username, password)
-        .initial_device_display_name("yande_popular_bot")
-        .send()
-        .await?;
-    let session = client.session();
-    if let Some(session) = session {
-        let session = serde_json::to_string(&session)?;
-        fs::write(session_file, session)?;
-    };
+/// 登录并找到默认房间，失败时按退避时间重试，然后在后台保持同步
+/// 会话文件和口令不匹配时重试没有意义，直接返回错误
+pub async fn init() -> Result<()> {
+    let mut backoff = connection::Backoff::default();
+    loop {
+        match try_init().await {
+            Ok(client) => {
+                start(client);
+                return Ok(());
+            }
+            Err(e) if e.is::<session::Mismatch>() => return Err(e),
+            Err(e) => {
+                log::error!("matrix init failed: {}, retry in {:?}", e, backoff.delay());
+                backoff.wait().await;
+            }
+        }
+    }
+}
+
+/// 和 `init` 相同，但失败时直接返回错误，用于一次性运行的子命令
+pub async fn init_once() -> Result<()> {
+    start(try_init().await?);
+    Ok(())
+}
+
+fn start(client: Client) {
+    e2ee::register_handlers(&client);
+    tokio::spawn(keys::maintain(client.clone()));
+    tokio::spawn(connection::supervise(client));
+}
+
+/// 只登录并处理设备验证，不加入房间也不响应命令，Ctrl-C 退出
+pub async fn verify() -> Result<()> {
+    let args = &args().matrix;
+    let client = login(
+        args.home_server_url(),
+        args.user(),
+        args.password.as_deref(),
+    )
+    .await?;
+    let client = CLIENT.get_or_init(|| client).clone();
+    e2ee::register_verification(&client);
+    // 验证需要通过管理接口确认
+    tokio::spawn(crate::admin::serve());
+    log::info!("waiting for verification requests, press Ctrl-C to exit");
+    tokio::select! {
+        _ = connection::supervise(client) => {}
+        _ = tokio::signal::ctrl_c() => log::info!("Ctrl-C received, exiting..."),
+    }
     Ok(())
 }
 
-pub fn room_init() -> Joined {
-    std::thread::spawn(|| {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(async move {
-            let args = args();
-            let client = CLIENT.get_or_init(client_init);
-            get_room(client, &args.room_id).await.unwrap()
-        })
-    })
-    .join()
-    .unwrap()
-}
-
-pub fn client_init() -> Client {
-    std::thread::spawn(|| {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(async move {
-            let args = args();
-
-            login(&args.home_server_url, &args.user, &args.password)
-                .await
-                .unwrap()
-        })
-    })
-    .join()
-    .unwrap()
+async fn try_init() -> Result<Client> {
+    let args = &args().matrix;
+    let client = match CLIENT.get() {
+        Some(client) => client.clone(),
+        None => {
+            let client = login(
+                args.home_server_url(),
+                args.user(),
+                args.password.as_deref(),
+            )
+            .await?;
+            CLIENT.get_or_init(|| client).clone()
+        }
+    };
+    client.sync_once(SyncSettings::new()).await?;
+    room::join(&client, args.room_id()).await?;
+    // 路由的房间加入失败不影响默认房间
+    for target in route::all_targets() {
+        if let Err(e) = room::join(&client, target).await {
+            log::error!("join route target {} failed: {}", target, e);
+        }
+    }
+    Ok(client)
+}
+
+/// 已登录并且最近一次同步成功
+pub fn is_ready() -> bool {
+    CLIENT.get().is_some() && connection::is_online()
+}
+
+/// `init` 完成之前返回错误
+pub fn client() -> Result<&'static Client> {
+    CLIENT
+        .get()
+        .ok_or(anyhow::anyhow!("matrix client not initialized"))
 }
@@ -0,0 +1,25 @@
+use matrix_sdk::{
+    ruma::events::{
+        reaction::OriginalSyncReactionEvent, room::redaction::OriginalSyncRoomRedactionEvent,
+    },
+    Client,
+};
+
+use crate::feedback;
+
+/// 对机器人发出的图片或说明的点赞类回应记为一次点赞，其他表情不计入
+pub async fn on_reaction(ev: OriginalSyncReactionEvent, client: Client) {
+    if client.user_id() == Some(&*ev.sender) || !feedback::is_like(&ev.content.relates_to.key) {
+        return;
+    }
+    let event_id = ev.content.relates_to.event_id.to_string();
+    feedback::record_reaction(&event_id, ev.sender.as_str(), Some(ev.event_id.as_str()))
+        .map(|_| ())
+        .unwrap_or_else(|e| log::error!("record reaction failed: {}", e));
+}
+
+/// 撤回的回应不再计入点赞，其他事件的撤回会被忽略
+pub async fn on_redaction(ev: OriginalSyncRoomRedactionEvent) {
+    feedback::remove_reaction(ev.redacts.as_str())
+        .unwrap_or_else(|e| log::error!("remove reaction failed: {}", e));
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use matrix_sdk::{
+    config::SyncSettings,
+    room::Room,
+    ruma::{
+        events::room::member::{MembershipState, StrippedRoomMemberEvent},
+        OwnedRoomId, RoomAliasId, RoomId, RoomOrAliasId, UserId,
+    },
+    Client,
+};
+
+use crate::{args, db::DB, yande::DB_HANDLE};
+
+/// 用命令退出的房间，重启后不会自动重新加入
+const LEFT_ROOMS: &str = "left_rooms";
+
+/// 配置中的房间名（ID 或别名）到房间 ID
+static ROOM_IDS: OnceLock<Mutex<HashMap<String, OwnedRoomId>>> = OnceLock::new();
+
+fn room_ids() -> &'static Mutex<HashMap<String, OwnedRoomId>> {
+    ROOM_IDS.get_or_init(Default::default)
+}
+
+/// 别名（#art:example.org）通过服务器解析，房间 ID 直接使用
+pub async fn resolve(client: &Client, room: &str) -> Result<OwnedRoomId> {
+    if let Some(room_id) = room_ids().lock().unwrap().get(room) {
+        return Ok(room_id.clone());
+    }
+    let room_id = if room.starts_with('#') {
+        client
+            .resolve_room_alias(<&RoomAliasId>::try_from(room)?)
+            .await?
+            .room_id
+    } else {
+        <&RoomId>::try_from(room)?.to_owned()
+    };
+    room_ids()
+        .lock()
+        .unwrap()
+        .insert(room.to_string(), room_id.clone());
+    Ok(room_id)
+}
+
+/// 已解析过的房间 ID，没有解析过时按房间 ID 处理
+pub fn lookup(room: &str) -> Result<OwnedRoomId> {
+    match room_ids().lock().unwrap().get(room) {
+        Some(room_id) => Ok(room_id.clone()),
+        None => Ok(<&RoomId>::try_from(room)?.to_owned()),
+    }
+}
+
+fn left_rooms() -> Result<Vec<OwnedRoomId>> {
+    let db = DB_HANDLE.get_or_init(DB::init);
+    Ok(db.get_value(LEFT_ROOMS)?.unwrap_or_default())
+}
+
+/// 记录用命令退出的房间，`left` 为 false 时取消记录
+pub fn set_left(room_id: &RoomId, left: bool) -> Result<()> {
+    let mut rooms = left_rooms()?;
+    rooms.retain(|id| id != room_id);
+    if left {
+        rooms.push(room_id.to_owned());
+    }
+    DB_HANDLE
+        .get_or_init(DB::init)
+        .set_value(LEFT_ROOMS, &rooms)
+}
+
+/// 还没有加入时加入房间，只在启动同步循环之前调用
+/// 用命令退出过的房间不会重新加入，需要重新邀请机器人
+pub async fn join(client: &Client, room: &str) -> Result<()> {
+    let room_id = resolve(client, room).await?;
+    if client.get_joined_room(&room_id).is_some() {
+        return Ok(());
+    }
+    if left_rooms()?.contains(&room_id) {
+        log::warn!(
+            "skip joining {}, the bot was told to leave it, invite it again to rejoin",
+            room
+        );
+        return Ok(());
+    }
+
+    log::info!("join room {}", room);
+    client
+        .join_room_by_id_or_alias(<&RoomOrAliasId>::try_from(room)?, &[])
+        .await?;
+    client.sync_once(SyncSettings::new()).await?;
+    client
+        .get_joined_room(&room_id)
+        .map(|_| ())
+        .ok_or(anyhow::anyhow!("join room {} failed", room))
+}
+
+/// 邀请者的用户 ID 或服务器在允许列表中
+fn invite_allowed(sender: &UserId) -> bool {
+    args()
+        .matrix
+        .invite_allowlist
+        .iter()
+        .any(|allowed| allowed == sender.as_str() || allowed == sender.server_name().as_str())
+}
+
+/// 自动接受允许列表中的用户发来的邀请
+pub async fn on_invite(ev: StrippedRoomMemberEvent, room: Room, client: Client) {
+    if ev.content.membership != MembershipState::Invite || client.user_id() != Some(&*ev.state_key)
+    {
+        return;
+    }
+    let Room::Invited(room) = room else {
+        return;
+    };
+    if !invite_allowed(&ev.sender) {
+        log::info!("ignore invite to {} from {}", room.room_id(), ev.sender);
+        return;
+    }
+
+    // 服务器处理邀请可能有延迟，加入失败时重试几次
+    tokio::spawn(async move {
+        for delay in [2, 4, 8, 16, 32] {
+            match room.accept_invitation().await {
+                Ok(()) => {
+                    log::info!("joined {} invited by {}", room.room_id(), ev.sender);
+                    set_left(room.room_id(), false)
+                        .unwrap_or_else(|e| log::error!("update left rooms failed: {}", e));
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("join {} failed: {}, retry in {}s", room.room_id(), e, delay);
+                    tokio::time::sleep(Duration::from_secs(delay)).await;
+                }
+            }
+        }
+        log::error!("give up joining {}", room.room_id());
+    });
+}
@@ -0,0 +1,156 @@
+use std::{fs, num::NonZeroU32, path::Path};
+
+use anyhow::Result;
+use matrix_sdk::Session;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const SALT_LEN: usize = 16;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// 加密后的会话文件
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Encrypted {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// 会话文件是否加密和是否设置了口令不一致，重试也无法恢复
+#[derive(Debug)]
+pub struct Mismatch(String);
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// 设置了口令时加密保存，否则保存为明文 JSON
+pub fn save(path: impl AsRef<Path>, session: &Session, passphrase: Option<&str>) -> Result<()> {
+    let data = serde_json::to_vec(session)?;
+    let data = match passphrase {
+        Some(passphrase) => serde_json::to_vec(&encrypt(&data, passphrase)?)?,
+        None => data,
+    };
+    fs::write(path, data)?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Session> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    let data = match passphrase {
+        Some(_) if serde_json::from_slice::<Session>(&data).is_ok() => {
+            return Err(Mismatch(format!(
+                "{} was saved without SESSION_PASSPHRASE, unset it, or stop the service and delete the session file together with db/matrix-sdk-state and db/matrix-sdk-crypto in DATA_DIR to log in again with encryption",
+                path.display()
+            ))
+            .into());
+        }
+        Some(passphrase) => decrypt(&serde_json::from_slice(&data)?, passphrase)?,
+        None if serde_json::from_slice::<Encrypted>(&data).is_ok() => {
+            return Err(Mismatch(format!(
+                "{} is encrypted, set the SESSION_PASSPHRASE it was saved with",
+                path.display()
+            ))
+            .into());
+        }
+        None => data,
+    };
+    Ok(serde_json::from_slice(&data)?)
+}
+
+fn key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ROUNDS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+fn encrypt(data: &[u8], passphrase: &str) -> Result<Encrypted> {
+    let rng = SystemRandom::new();
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut salt)
+        .and_then(|_| rng.fill(&mut nonce))
+        .map_err(|_| anyhow::anyhow!("generate random failed"))?;
+
+    let mut ciphertext = data.to_vec();
+    key(passphrase, &salt)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut ciphertext,
+        )
+        .map_err(|_| anyhow::anyhow!("encrypt session failed"))?;
+
+    Ok(Encrypted {
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+fn decrypt(encrypted: &Encrypted, passphrase: &str) -> Result<Vec<u8>> {
+    let salt = hex::decode(&encrypted.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&hex::decode(&encrypted.nonce)?)
+        .map_err(|_| anyhow::anyhow!("invalid nonce"))?;
+    let mut data = hex::decode(&encrypted.ciphertext)?;
+
+    let plain = key(passphrase, &salt)
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| anyhow::anyhow!("decrypt session failed, wrong passphrase?"))?;
+    Ok(plain.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let data = b"{\"access_token\":\"secret\"}";
+        let encrypted = encrypt(data, "passphrase").unwrap();
+        assert!(!encrypted.ciphertext.contains(&hex::encode("secret")));
+        assert_eq!(decrypt(&encrypted, "passphrase").unwrap(), data);
+        assert!(decrypt(&encrypted, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let session = Session {
+            access_token: "secret".to_string(),
+            refresh_token: None,
+            user_id: "@bot:example.org".try_into().unwrap(),
+            device_id: "DEVICE".into(),
+        };
+        let path = std::env::temp_dir().join(format!("session_test_{}", std::process::id()));
+
+        save(&path, &session, Some("passphrase")).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+        let loaded = load(&path, Some("passphrase")).unwrap();
+        assert_eq!(loaded.access_token, session.access_token);
+        assert_eq!(loaded.user_id, session.user_id);
+        assert_eq!(loaded.device_id, session.device_id);
+        assert!(load(&path, Some("wrong")).is_err());
+        assert!(load(&path, None).unwrap_err().is::<Mismatch>());
+
+        save(&path, &session, None).unwrap();
+        assert!(load(&path, Some("passphrase"))
+            .unwrap_err()
+            .is::<Mismatch>());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::Result;
+use image::{DynamicImage, RgbaImage};
+
+use crate::args;
+
+/// 模糊缩略图的宽度，客户端会自行放大
+const BLUR_WIDTH: u32 = 64;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// 正常发送
+    Normal,
+    /// 标记为剧透，图片使用模糊缩略图
+    Spoiler,
+    /// 不发送
+    Skip,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SpoilerArgs {
+    /// 可疑和限制级帖子的默认处理方式
+    #[arg(long, env = "NSFW_POLICY", value_enum, default_value = "normal")]
+    pub nsfw_policy: Policy,
+
+    /// 按房间设置处理方式，格式为 `房间ID=normal|spoiler|skip`，多个用分号分隔
+    #[arg(long, env = "NSFW_ROOM_POLICY", value_delimiter = ';', value_parser = parse_room_policy)]
+    pub nsfw_room_policy: Vec<(String, Policy)>,
+}
+
+fn parse_room_policy(s: &str) -> Result<(String, Policy)> {
+    let (room, policy) = s
+        .rsplit_once('=')
+        .ok_or(anyhow::anyhow!("invalid room policy: {s}"))?;
+    let policy = <Policy as clap::ValueEnum>::from_str(policy.trim(), true)
+        .map_err(|e| anyhow::anyhow!("invalid room policy {s}: {e}"))?;
+    Ok((room.trim().to_string(), policy))
+}
+
+/// 全年龄的帖子总是正常发送
+pub fn policy(room_id: &str, rating: &str) -> Policy {
+    if !matches!(rating, "q" | "e") {
+        return Policy::Normal;
+    }
+    let config = &args().matrix.spoiler;
+    config
+        .nsfw_room_policy
+        .iter()
+        .find(|(room, _)| room == room_id)
+        .map(|(_, policy)| *policy)
+        .unwrap_or(config.nsfw_policy)
+}
+
+/// MSC4193 剧透和 MSC3725 内容警告，加在 m.image 事件的 content 中
+pub fn mark(content: &mut serde_json::Value, rating: &str) {
+    let reason = match rating {
+        "e" => "explicit",
+        _ => "questionable",
+    };
+    content["page.codeberg.everypizza.msc4193.spoiler"] = true.into();
+    content["page.codeberg.everypizza.msc4193.spoiler.reason"] = reason.into();
+    content["town.robin.msc3725.content_warning"] = serde_json::json!({
+        "type": "town.robin.msc3725.nudity",
+    });
+}
+
+/// 从 blurhash 还原出模糊的缩略图
+pub fn blurred_thumbnail(blurhash: &str, width: u32, height: u32) -> Result<DynamicImage> {
+    let thumb_height = (BLUR_WIDTH * height / width.max(1)).max(1);
+    let pixels = blurhash::decode(blurhash, BLUR_WIDTH, thumb_height, 1.0)?;
+    let image = RgbaImage::from_raw(BLUR_WIDTH, thumb_height, pixels)
+        .ok_or(anyhow::anyhow!("invalid blurhash pixels"))?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
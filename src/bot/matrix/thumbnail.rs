@@ -0,0 +1,21 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+
+/// 长边不超过 `size` 的缩略图，原图不大于 `size` 或 `size` 为 0 时返回 `None`
+pub fn generate(image: &DynamicImage, size: u32) -> Option<DynamicImage> {
+    let (width, height) = image.dimensions();
+    if size == 0 || width.max(height) <= size {
+        return None;
+    }
+    Some(image.thumbnail(size, size))
+}
+
+pub fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    image
+        .to_rgb8()
+        .write_to(&mut data, ImageOutputFormat::Jpeg(80))?;
+    Ok(data.into_inner())
+}
@@ -1,10 +1,132 @@
 #[cfg(feature = "matrix")]
 pub mod matrix;
 #[cfg(feature = "voce")]
-mod voce;
+pub mod voce;
 
-#[cfg(feature = "matrix")]
-pub use matrix::*;
+#[cfg(not(any(feature = "matrix", feature = "voce")))]
+compile_error!("at least one of the `matrix` and `voce` features is required");
 
-#[cfg(feature = "voce")]
-pub use voce::*;
+use std::{path::PathBuf, sync::OnceLock};
+
+use axum::Router;
+use tokio::sync::Mutex;
+
+use crate::{args, caption::Caption};
+
+/// 同一时间只发送一个帖子，多线程时不同帖子的消息不会交错
+static SEND_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+/// 启动时选定的后端，重新加载配置不会切换
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    #[cfg(feature = "matrix")]
+    Matrix,
+    #[cfg(feature = "voce")]
+    Voce,
+}
+
+/// 编译了 Matrix 时默认使用 Matrix
+impl Default for Backend {
+    fn default() -> Self {
+        #[cfg(feature = "matrix")]
+        return Backend::Matrix;
+        #[cfg(not(feature = "matrix"))]
+        return Backend::Voce;
+    }
+}
+
+pub fn backend() -> Backend {
+    *BACKEND.get_or_init(|| args().backend)
+}
+
+/// Matrix 需要登录并加入房间，VoceChat 不需要准备
+pub async fn init() -> anyhow::Result<()> {
+    #[cfg(feature = "matrix")]
+    if backend() == Backend::Matrix {
+        log::info!("login");
+        matrix::init().await?;
+        log::info!("login success");
+    }
+    Ok(())
+}
+
+/// 一次性运行的子命令使用，登录失败时直接返回错误，不重试
+pub async fn init_once() -> anyhow::Result<()> {
+    #[cfg(feature = "matrix")]
+    if backend() == Backend::Matrix {
+        log::info!("login");
+        matrix::init_once().await?;
+        log::info!("login success");
+    }
+    Ok(())
+}
+
+/// 只运行 Matrix 的设备验证流程，用于首次登录后验证机器人的设备
+pub async fn verify() -> anyhow::Result<()> {
+    match backend() {
+        #[cfg(feature = "matrix")]
+        Backend::Matrix => matrix::verify().await,
+        #[cfg(feature = "voce")]
+        Backend::Voce => anyhow::bail!("verify is only supported by the matrix backend"),
+    }
+}
+
+/// 按顺序发送说明和整个家族的图片到 `target` 房间或频道，返回发出的消息 ID，用于记录回应
+/// 中途失败时同时返回已经发出的消息和错误
+/// `rating` 为帖子分级，Matrix 据此决定是否标记剧透
+pub async fn send_post(
+    target: &str,
+    rating: &str,
+    caption: &Caption,
+    files: &[PathBuf],
+) -> (Vec<String>, anyhow::Result<()>) {
+    let _guard = SEND_LOCK.get_or_init(|| Mutex::new(())).lock().await;
+    let mut messages = Vec::new();
+    let result = match backend() {
+        #[cfg(feature = "matrix")]
+        Backend::Matrix => {
+            matrix::send_gallery(target, rating, caption, files, &mut messages).await
+        }
+        #[cfg(feature = "voce")]
+        Backend::Voce => voce::send_gallery(target, rating, caption, files, &mut messages).await,
+    };
+    (messages, result)
+}
+
+/// 没有路由规则匹配时发送到的房间或频道
+pub fn default_target() -> &'static str {
+    match backend() {
+        #[cfg(feature = "matrix")]
+        Backend::Matrix => matrix::default_target(),
+        #[cfg(feature = "voce")]
+        Backend::Voce => voce::default_target(),
+    }
+}
+
+pub async fn send_dm(user: &str, caption: &Caption) -> anyhow::Result<()> {
+    match backend() {
+        #[cfg(feature = "matrix")]
+        Backend::Matrix => matrix::send_dm(user, caption).await,
+        #[cfg(feature = "voce")]
+        Backend::Voce => voce::send_dm(user, caption).await,
+    }
+}
+
+pub fn is_ready() -> bool {
+    match backend() {
+        #[cfg(feature = "matrix")]
+        Backend::Matrix => matrix::is_ready(),
+        #[cfg(feature = "voce")]
+        Backend::Voce => voce::is_ready(),
+    }
+}
+
+/// VoceChat 通过 Webhook 接收命令和回应，Matrix 通过同步接收
+pub fn webhook_router() -> Router {
+    #[cfg(feature = "voce")]
+    if backend() == Backend::Voce {
+        return voce::webhook_router();
+    }
+    Router::new()
+}
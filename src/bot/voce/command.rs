@@ -0,0 +1,143 @@
+use axum::{extract::Query, http::StatusCode, routing::get, Json, Router};
+use serde::Deserialize;
+
+use crate::{args, command::Command, feedback};
+
+/// VoceChat 机器人 Webhook 推送的消息
+#[derive(Debug, Deserialize)]
+struct Message {
+    from_uid: i64,
+    detail: Detail,
+    target: Target,
+}
+
+/// 普通消息为 `normal`，点赞为 `reaction`，此时 `mid` 是被回应的消息
+#[derive(Debug, Deserialize)]
+struct Detail {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    content_type: String,
+    mid: Option<i64>,
+    detail: Option<Reaction>,
+}
+
+/// 点赞时 `action` 为表情
+#[derive(Debug, Deserialize)]
+struct Reaction {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    action: String,
+}
+
+/// 群组消息带有 gid，私聊消息只有机器人自己的 uid
+#[derive(Debug, Deserialize)]
+struct Target {
+    gid: Option<i64>,
+}
+
+/// 在 VoceChat 的机器人设置中填写 {PUBLIC_URL}/voce/webhook?token=<VOCE_WEBHOOK_TOKEN>
+#[derive(Debug, Deserialize)]
+struct Token {
+    token: Option<String>,
+}
+
+impl Token {
+    /// 和 VOCE_WEBHOOK_TOKEN 比较，用固定时间的比较避免逐字节猜测
+    fn is_valid(&self) -> bool {
+        let (Some(expected), Some(token)) = (&args().voce.webhook_token, &self.token) else {
+            return false;
+        };
+        expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+pub fn webhook_router() -> Router {
+    if args().voce.webhook_token.is_none() {
+        log::warn!("VOCE_WEBHOOK_TOKEN is not set, VoceChat webhook is disabled");
+        return Router::new();
+    }
+    // 保存 Webhook 地址时 VoceChat 会先发送 GET 请求检查是否返回 200
+    Router::new().route("/voce/webhook", get(check).post(receive))
+}
+
+async fn check(Query(token): Query<Token>) -> StatusCode {
+    if token.is_valid() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+async fn receive(Query(token): Query<Token>, Json(msg): Json<Message>) -> StatusCode {
+    if !token.is_valid() {
+        return StatusCode::UNAUTHORIZED;
+    }
+    // 命令可能执行较久，先返回，避免 VoceChat 重试
+    tokio::spawn(handle(msg));
+    StatusCode::OK
+}
+
+async fn handle(msg: Message) {
+    if msg.detail.kind == "reaction" {
+        if let (Some(mid), Some(reaction)) = (msg.detail.mid, &msg.detail.detail) {
+            if reaction.kind != "like" || !feedback::is_like(&reaction.action) {
+                return;
+            }
+            feedback::record_reaction(&mid.to_string(), &msg.from_uid.to_string(), None)
+                .map(|_| ())
+                .unwrap_or_else(|e| log::error!("record reaction failed: {}", e));
+        }
+        return;
+    }
+    if !msg.detail.content_type.starts_with("text/") {
+        return;
+    }
+    let Some(command) = Command::parse(strip_mention(&msg.detail.content)) else {
+        return;
+    };
+
+    let reply = match command {
+        Ok(command)
+            if command.requires_admin() && !args().admins.contains(&msg.from_uid.to_string()) =>
+        {
+            "没有权限".to_string()
+        }
+        Ok(command) => {
+            log::info!("{} run command: {:?}", msg.from_uid, command);
+            command
+                .execute(&msg.from_uid.to_string())
+                .await
+                .unwrap_or_else(|e| format!("执行失败：{}", e))
+        }
+        Err(e) => e.to_string(),
+    };
+
+    let result = match msg.target.gid {
+        Some(gid) => super::send_to_group(&gid.to_string(), &reply).await,
+        None => super::send_to_user(msg.from_uid, &reply).await,
+    };
+    if let Err(e) = result {
+        log::error!("reply command failed: {}", e);
+    }
+}
+
+/// 群组中 @机器人 的消息以 `{@uid}` 开头
+fn strip_mention(content: &str) -> &str {
+    let content = content.trim_start();
+    match content
+        .strip_prefix("{@")
+        .and_then(|rest| rest.split_once('}'))
+    {
+        Some((_, rest)) => rest.trim_start(),
+        None => content,
+    }
+}
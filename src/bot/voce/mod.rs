@@ -1,20 +1,75 @@
+mod command;
+
 use anyhow::Result;
 
 use reqwest::header::HeaderMap;
 use reqwest::multipart::{self, Form};
 use reqwest::{header, Client, ClientBuilder, Method};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
-use crate::args;
+use crate::{args, caption::Caption};
+
+pub use command::webhook_router;
 
 static CLIENT: OnceLock<Client> = OnceLock::new();
 
 const CHUNK_SIZE: usize = 200 * 1024;
 
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "VoceChat")]
+pub struct VoceArgs {
+    /// 发送到的频道ID
+    #[arg(short, long, env = "CHANNEL_ID")]
+    pub channel_id: Option<String>,
+
+    /// 机器人API_KEY
+    #[arg(short, long, env = "API_KEY")]
+    pub api_key: Option<String>,
+
+    /// 服务器域名
+    #[arg(short, long, env = "SERVER_DOMAIN")]
+    pub server_domain: Option<String>,
+
+    /// Webhook 密钥，VoceChat 中的 Webhook 地址需要带上 ?token=<密钥>，不设置时不接收 Webhook
+    #[arg(long, env = "VOCE_WEBHOOK_TOKEN", hide_env_values = true)]
+    pub webhook_token: Option<String>,
+}
+
+impl VoceArgs {
+    /// 选择 VoceChat 后端时这些参数必须设置，检查后可以直接取用
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        for (value, name) in [
+            (&self.channel_id, "CHANNEL_ID"),
+            (&self.api_key, "API_KEY"),
+            (&self.server_domain, "SERVER_DOMAIN"),
+        ] {
+            if value.is_none() {
+                return Err(clap::Error::raw(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    format!("{name} is required for the voce backend\n"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn channel_id(&self) -> &str {
+        self.channel_id.as_deref().unwrap_or_default()
+    }
+
+    pub fn api_key(&self) -> &str {
+        self.api_key.as_deref().unwrap_or_default()
+    }
+
+    pub fn server_domain(&self) -> &str {
+        self.server_domain.as_deref().unwrap_or_default()
+    }
+}
+
 #[derive(Debug, serde::Serialize)]
 pub struct PrepareUpload {
     pub content_type: String,
@@ -62,7 +117,7 @@ fn client_builder() -> Client {
 
     headers.insert(
         header::HeaderName::from_static("x-api-key"),
-        header::HeaderValue::from_str(&args().api_key).unwrap(),
+        header::HeaderValue::from_str(args().voce.api_key()).unwrap(),
     );
 
     ClientBuilder::new()
@@ -72,7 +127,7 @@ fn client_builder() -> Client {
 }
 
 async fn prepare_upload(fileinfo: PrepareUpload) -> Result<String> {
-    let url = format!("{}/api/bot/file/prepare", &args().server_domain);
+    let url = format!("{}/api/bot/file/prepare", args().voce.server_domain());
     let client = CLIENT.get_or_init(client_builder);
     let resp = client
         .post(&url)
@@ -87,7 +142,7 @@ async fn prepare_upload(fileinfo: PrepareUpload) -> Result<String> {
 }
 
 async fn upload(file_path: &Path, file_id: &str) -> Result<UploadResponse> {
-    let url = format!("{}/api/bot/file/upload", &args().server_domain);
+    let url = format!("{}/api/bot/file/upload", args().voce.server_domain());
 
     let client = CLIENT.get_or_init(client_builder);
     let mut headers = header::HeaderMap::new();
@@ -132,10 +187,10 @@ async fn upload(file_path: &Path, file_id: &str) -> Result<UploadResponse> {
     Err(anyhow::anyhow!("upload failed"))
 }
 
-async fn send_file_msg(channel_id: &str, msg: &str) -> Result<()> {
+async fn send_file_msg(channel_id: &str, msg: &str) -> Result<i64> {
     let url = format!(
         "{}/api/bot/send_to_group/{}",
-        &args().server_domain,
+        args().voce.server_domain(),
         channel_id
     );
     let client = CLIENT.get_or_init(client_builder);
@@ -151,18 +206,53 @@ async fn send_file_msg(channel_id: &str, msg: &str) -> Result<()> {
         .body(msg.to_string())
         .headers(header)
         .build()?;
-    client.execute(resp).await?.error_for_status()?;
+    let mid = client
+        .execute(resp)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
 
-    Ok(())
+    Ok(mid)
 }
 
-pub async fn send_msg(msg: &str) -> Result<()> {
-    let channel_id = &args().channel_id;
+/// 发送 Markdown 消息到群组，返回消息 ID
+pub async fn send_to_group(gid: &str, msg: &str) -> Result<i64> {
     let url = format!(
         "{}/api/bot/send_to_group/{}",
-        &args().server_domain,
-        channel_id
+        args().voce.server_domain(),
+        gid
     );
+    send_markdown(&url, msg).await
+}
+
+/// 发送 Markdown 私聊消息给用户
+pub async fn send_to_user(uid: i64, msg: &str) -> Result<i64> {
+    let url = format!(
+        "{}/api/bot/send_to_user/{}",
+        args().voce.server_domain(),
+        uid
+    );
+    send_markdown(&url, msg).await
+}
+
+/// 每次发送都用 API_KEY 请求，不需要保持登录
+pub fn is_ready() -> bool {
+    true
+}
+
+/// 没有路由规则匹配时发送到的频道
+pub fn default_target() -> &'static str {
+    args().voce.channel_id()
+}
+
+/// 私信用户，`user` 为 UID
+pub async fn send_dm(user: &str, caption: &Caption) -> Result<()> {
+    send_to_user(user.parse()?, &caption.markdown).await?;
+    Ok(())
+}
+
+async fn send_markdown(url: &str, msg: &str) -> Result<i64> {
     let client = CLIENT.get_or_init(client_builder);
 
     let mut header = HeaderMap::new();
@@ -172,16 +262,40 @@ pub async fn send_msg(msg: &str) -> Result<()> {
     );
 
     let resp = client
-        .request(Method::POST, &url)
+        .request(Method::POST, url)
         .body(msg.to_string())
         .headers(header)
         .build()?;
-    client.execute(resp).await?.error_for_status()?;
+    let mid = client
+        .execute(resp)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
 
+    Ok(mid)
+}
+
+/// VoceChat 的一条消息只能带一个文件，依次发送说明和图片，各条消息的 ID 发出后立即加入 `messages`
+pub async fn send_gallery(
+    channel_id: &str,
+    _rating: &str,
+    caption: &Caption,
+    files: &[PathBuf],
+    messages: &mut Vec<String>,
+) -> Result<()> {
+    messages.push(
+        send_to_group(channel_id, &caption.markdown)
+            .await?
+            .to_string(),
+    );
+    for file in files {
+        messages.push(send_attachment(channel_id, file).await?.to_string());
+    }
     Ok(())
 }
 
-pub async fn send_attachment(file_path: &Path) -> Result<()> {
+async fn send_attachment(channel_id: &str, file_path: &Path) -> Result<i64> {
     let mime = mime_guess::from_path(file_path)
         .first_or_octet_stream()
         .to_string();
@@ -192,12 +306,9 @@ pub async fn send_attachment(file_path: &Path) -> Result<()> {
     let file_id = prepare_upload(fileinfo).await?;
     let upload_path = upload(file_path, &file_id).await?.path;
 
-    let channel_id = &args().channel_id;
     let payload = serde_json::json!({
         "path":upload_path,
     });
     let msg = serde_json::to_string(&payload).unwrap();
-    send_file_msg(channel_id, &msg).await?;
-    std::fs::remove_file(file_path)?;
-    Ok(())
+    send_file_msg(channel_id, &msg).await
 }
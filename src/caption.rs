@@ -0,0 +1,261 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use minijinja::Environment;
+
+use crate::{args, yande::ImgData};
+
+/// 重新加载配置后清空，下次渲染时按新的模板重建
+static ENV: Mutex<Option<Arc<Environment<'static>>>> = Mutex::new(None);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Locale {
+    Zh,
+    En,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CaptionArgs {
+    /// 内置说明模板的语言
+    #[arg(long, env = "CAPTION_LOCALE", value_enum, default_value = "zh")]
+    pub caption_locale: Locale,
+
+    /// Markdown 说明模板（minijinja 语法），用于 VoceChat
+    /// 可用变量：id url artist copyright character general tags score rating rating_code
+    /// width height file_size file_size_bytes source images
+    #[arg(long, env = "CAPTION_MARKDOWN")]
+    pub caption_markdown: Option<String>,
+
+    /// HTML 说明模板，用于 Matrix 的 formatted_body，变量会自动转义
+    #[arg(long, env = "CAPTION_HTML")]
+    pub caption_html: Option<String>,
+
+    /// 纯文本说明模板，用于 Matrix 的 body 和 Webhook
+    #[arg(long, env = "CAPTION_TEXT")]
+    pub caption_text: Option<String>,
+}
+
+/// 同一个帖子的三种格式的说明，各个发送端按需取用
+#[derive(Debug, Clone, Default)]
+pub struct Caption {
+    pub markdown: String,
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Context {
+    id: i64,
+    url: String,
+    artist: Vec<String>,
+    copyright: Vec<String>,
+    character: Vec<String>,
+    general: Vec<String>,
+    tags: Vec<String>,
+    score: u64,
+    rating: &'static str,
+    rating_code: String,
+    width: u32,
+    height: u32,
+    file_size: String,
+    file_size_bytes: u64,
+    source: String,
+    images: usize,
+}
+
+impl Context {
+    fn new(id: i64, img_data: &ImgData, locale: Locale) -> Self {
+        let post = img_data.posts.get(&id).cloned().unwrap_or_default();
+        let collect = |kind: &str| -> Vec<String> {
+            post.tags_of(kind)
+                .iter()
+                .map(|tag| tag.to_string())
+                .collect()
+        };
+        let rating = match (post.rating.as_str(), locale) {
+            ("s", Locale::Zh) => "全年龄",
+            ("q", Locale::Zh) => "可疑",
+            ("e", Locale::Zh) => "限制级",
+            ("s", Locale::En) => "Safe",
+            ("q", Locale::En) => "Questionable",
+            ("e", Locale::En) => "Explicit",
+            _ => "",
+        };
+
+        Context {
+            id,
+            url: format!("https://yande.re/post/show/{id}"),
+            artist: collect("artist"),
+            copyright: collect("copyright"),
+            character: collect("character"),
+            general: collect("general"),
+            tags: post.tag_list().iter().map(|tag| tag.to_string()).collect(),
+            score: img_data.score,
+            rating,
+            rating_code: post.rating.clone(),
+            width: post.width,
+            height: post.height,
+            file_size: human_size(post.file_size),
+            file_size_bytes: post.file_size,
+            source: post.source.clone(),
+            images: img_data.url.len(),
+        }
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let config = &args().caption;
+    let (markdown, html, text) = match config.caption_locale {
+        Locale::Zh => (ZH_MARKDOWN, ZH_HTML, ZH_TEXT),
+        Locale::En => (EN_MARKDOWN, EN_HTML, EN_TEXT),
+    };
+
+    let mut env = Environment::new();
+    for (name, custom, default) in [
+        ("caption.md", &config.caption_markdown, markdown),
+        ("caption.html", &config.caption_html, html),
+        ("caption.txt", &config.caption_text, text),
+    ] {
+        let source = custom.clone().unwrap_or_else(|| default.to_string());
+        if let Err(e) = env.add_template_owned(name, source) {
+            log::error!("invalid {} template, fallback to default: {}", name, e);
+            env.add_template_owned(name, default.to_string())
+                .expect("default caption template should be valid");
+        }
+    }
+    env
+}
+
+pub fn render(id: i64, img_data: &ImgData) -> Result<Caption> {
+    let env = ENV
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(environment()))
+        .clone();
+    let context = Context::new(id, img_data, args().caption.caption_locale);
+
+    Ok(Caption {
+        markdown: env.get_template("caption.md")?.render(&context)?,
+        html: env.get_template("caption.html")?.render(&context)?,
+        text: env.get_template("caption.txt")?.render(&context)?,
+    })
+}
+
+pub fn reset() {
+    ENV.lock().unwrap().take();
+}
+
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        0 => String::new(),
+        b if b < 1024 * 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{:.1} MB", b as f64 / 1024.0 / 1024.0),
+    }
+}
+
+const ZH_MARKDOWN: &str = "来源：[{{ url }}]({{ url }})
+{%- if artist %}
+画师：{{ artist | join(\", \") }}{% endif %}
+{%- if copyright %}
+作品：{{ copyright | join(\", \") }}{% endif %}
+{%- if character %}
+角色：{{ character | join(\", \") }}{% endif %}
+评分：{{ score }}{% if rating %} · 分级：{{ rating }}{% endif %}
+{%- if width %} · 尺寸：{{ width }}×{{ height }}{% endif %}
+{%- if file_size %} · 大小：{{ file_size }}{% endif %}";
+
+const ZH_HTML: &str = "来源：<a href=\"{{ url }}\">{{ url }}</a>
+{%- if artist %}<br>画师：{{ artist | join(\", \") }}{% endif %}
+{%- if copyright %}<br>作品：{{ copyright | join(\", \") }}{% endif %}
+{%- if character %}<br>角色：{{ character | join(\", \") }}{% endif %}
+<br>评分：{{ score }}{% if rating %} · 分级：{{ rating }}{% endif %}
+{%- if width %} · 尺寸：{{ width }}×{{ height }}{% endif %}
+{%- if file_size %} · 大小：{{ file_size }}{% endif %}";
+
+const ZH_TEXT: &str = "来源：{{ url }}
+{%- if artist %}
+画师：{{ artist | join(\", \") }}{% endif %}
+{%- if copyright %}
+作品：{{ copyright | join(\", \") }}{% endif %}
+{%- if character %}
+角色：{{ character | join(\", \") }}{% endif %}
+评分：{{ score }}{% if rating %} · 分级：{{ rating }}{% endif %}
+{%- if width %} · 尺寸：{{ width }}×{{ height }}{% endif %}
+{%- if file_size %} · 大小：{{ file_size }}{% endif %}";
+
+const EN_MARKDOWN: &str = "Source: [{{ url }}]({{ url }})
+{%- if artist %}
+Artist: {{ artist | join(\", \") }}{% endif %}
+{%- if copyright %}
+Copyright: {{ copyright | join(\", \") }}{% endif %}
+{%- if character %}
+Character: {{ character | join(\", \") }}{% endif %}
+Score: {{ score }}{% if rating %} · Rating: {{ rating }}{% endif %}
+{%- if width %} · Size: {{ width }}×{{ height }}{% endif %}
+{%- if file_size %} · {{ file_size }}{% endif %}";
+
+const EN_HTML: &str = "Source: <a href=\"{{ url }}\">{{ url }}</a>
+{%- if artist %}<br>Artist: {{ artist | join(\", \") }}{% endif %}
+{%- if copyright %}<br>Copyright: {{ copyright | join(\", \") }}{% endif %}
+{%- if character %}<br>Character: {{ character | join(\", \") }}{% endif %}
+<br>Score: {{ score }}{% if rating %} · Rating: {{ rating }}{% endif %}
+{%- if width %} · Size: {{ width }}×{{ height }}{% endif %}
+{%- if file_size %} · {{ file_size }}{% endif %}";
+
+const EN_TEXT: &str = "Source: {{ url }}
+{%- if artist %}
+Artist: {{ artist | join(\", \") }}{% endif %}
+{%- if copyright %}
+Copyright: {{ copyright | join(\", \") }}{% endif %}
+{%- if character %}
+Character: {{ character | join(\", \") }}{% endif %}
+Score: {{ score }}{% if rating %} · Rating: {{ rating }}{% endif %}
+{%- if width %} · Size: {{ width }}×{{ height }}{% endif %}
+{%- if file_size %} · {{ file_size }}{% endif %}";
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+
+    use super::*;
+    use crate::yande::Post;
+
+    #[test]
+    fn test_default_template() {
+        let post = Post {
+            id: 1,
+            tags: "foo artist_a".to_string(),
+            rating: "q".to_string(),
+            width: 100,
+            height: 200,
+            tag_types: [("artist_a".to_string(), "artist".to_string())].into(),
+            ..Default::default()
+        };
+        let img_data = ImgData {
+            score: 80,
+            url: VecDeque::from([(1, String::new())]),
+            posts: HashMap::from([(1, post)]),
+        };
+
+        let mut env = Environment::new();
+        env.add_template("caption.md", ZH_MARKDOWN).unwrap();
+        env.add_template("caption.html", EN_HTML).unwrap();
+        let context = Context::new(1, &img_data, Locale::Zh);
+
+        let markdown = env
+            .get_template("caption.md")
+            .unwrap()
+            .render(&context)
+            .unwrap();
+        assert_eq!(
+            markdown,
+            "来源：[https://yande.re/post/show/1](https://yande.re/post/show/1)\n画师：artist_a\n评分：80 · 分级：可疑 · 尺寸：100×200"
+        );
+        let html = env
+            .get_template("caption.html")
+            .unwrap()
+            .render(&context)
+            .unwrap();
+        assert!(html.contains("<br>Artist: artist_a"));
+    }
+}
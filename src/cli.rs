@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::{db::DB, yande};
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// 持续运行，按间隔扫描并推送，不指定子命令时的默认行为
+    Run,
+    /// 扫描热门列表并推送
+    Scan {
+        /// 只扫描一次，推送完成后退出
+        #[arg(long)]
+        once: bool,
+
+        /// 只扫描一次，不推送也不记录，把处理后的图片和报告写到预览目录，服务运行时也可以使用
+        #[arg(long)]
+        dry_run: bool,
+
+        /// 预览目录，默认为数据目录下的 preview/<时间>
+        #[arg(long, requires = "dry_run")]
+        preview_dir: Option<PathBuf>,
+    },
+    /// 推送指定的帖子和它所在的家族，不检查是否推送过
+    Post { id: i64 },
+    /// 输出帖子所在的家族、评分和图片地址，不推送
+    Info { id: i64 },
+    /// 数据库维护，需要先停止运行中的服务
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// 只运行 Matrix 的设备验证流程，通过管理接口确认
+    Verify,
+    /// 检查运行中的服务是否就绪，用于 Docker 的 HEALTHCHECK
+    Healthcheck,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum DbCommand {
+    /// 输出每个表的记录数和数据库大小
+    Stats,
+    /// 删除帖子的扫描和推送记录，之后可以再次推送
+    Forget { id: i64 },
+}
+
+pub async fn info(id: i64) -> Result<()> {
+    let (id, img_data) = yande::get_image_info(id).await?;
+    println!("family: {}", id);
+    println!("score: {}", img_data.score);
+    for (img_id, url) in img_data.url.iter() {
+        match img_data.posts.get(img_id) {
+            Some(post) => {
+                println!("{} [{}] {}", img_id, post.rating, url);
+                println!("    {}", post.tags);
+            }
+            None => println!("{} {}", img_id, url),
+        }
+    }
+    Ok(())
+}
+
+pub fn db(command: &DbCommand) -> Result<()> {
+    let db = yande::DB_HANDLE.get_or_init(DB::init);
+    match command {
+        DbCommand::Stats => {
+            for (name, count) in db.stats()? {
+                println!("{:<16} {}", name, count);
+            }
+            println!("{:<16} {} bytes", "size", db.size_on_disk()?);
+        }
+        DbCommand::Forget { id } => {
+            let forgotten = db.forget(*id)?;
+            if forgotten.is_empty() {
+                println!("{} has not been seen", id);
+            } else {
+                let ids: Vec<_> = forgotten.iter().map(i64::to_string).collect();
+                println!("forgot {}", ids.join(", "));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use anyhow::Result;
+
+use crate::{control, db::DB, feedback, subscription, yande};
+
+const HELP: &str = "可用命令：
+- status / stats：查看运行状态
+- pause / resume：暂停或恢复定时扫描
+- scan now：立即扫描一次
+- threshold [分数]：查看或设置最低评分
+- blacklist [add|del] [标签]：查看或修改标签黑名单
+- post <ID>：立即发送指定帖子（忽略去重）
+- search <标签>：搜索 yande.re
+- feedback：查看最受欢迎和最不受欢迎的标签
+- sub <标签...> [分数]：订阅同时带有这些标签的帖子，评分达到后私信通知
+- unsub <标签...>：取消订阅
+- subs：查看自己的订阅
+- leave [房间]：退出当前或指定的房间（仅 Matrix）";
+
+/// 聊天中的管理命令，Matrix 和 VoceChat 共用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Status,
+    Pause,
+    Resume,
+    Scan,
+    Threshold(Option<u64>),
+    Blacklist,
+    BlacklistAdd(String),
+    BlacklistRemove(String),
+    Post(i64),
+    Search(String),
+    Feedback,
+    Subscribe(Vec<String>, Option<u64>),
+    Unsubscribe(Vec<String>),
+    Subscriptions,
+    Leave(Option<String>),
+}
+
+impl Command {
+    /// 以 `!` 或 `/` 开头的消息才是命令，其他消息返回 `None`
+    pub fn parse(text: &str) -> Option<Result<Command>> {
+        let text = text.trim();
+        let text = text.strip_prefix('!').or(text.strip_prefix('/'))?;
+        let mut words = text.split_whitespace();
+        let name = words.next()?.to_lowercase();
+        let rest: Vec<&str> = words.collect();
+
+        let command = match (name.as_str(), rest.as_slice()) {
+            ("help", _) => Ok(Command::Help),
+            ("status" | "stats", _) => Ok(Command::Status),
+            ("pause", _) => Ok(Command::Pause),
+            ("resume", _) => Ok(Command::Resume),
+            ("scan", []) | ("scan", ["now"]) => Ok(Command::Scan),
+            ("threshold", []) => Ok(Command::Threshold(None)),
+            ("threshold", [score]) => score
+                .parse()
+                .map(|score| Command::Threshold(Some(score)))
+                .map_err(|_| anyhow::anyhow!("无效的分数：{}", score)),
+            ("blacklist", []) | ("blacklist", ["list"]) => Ok(Command::Blacklist),
+            ("blacklist", ["add", tag]) => Ok(Command::BlacklistAdd(tag.to_string())),
+            ("blacklist", ["del" | "remove", tag]) => Ok(Command::BlacklistRemove(tag.to_string())),
+            ("post", [id]) => id
+                .trim_start_matches('#')
+                .parse()
+                .map(Command::Post)
+                .map_err(|_| anyhow::anyhow!("无效的帖子 ID：{}", id)),
+            ("search", tags) if !tags.is_empty() => Ok(Command::Search(tags.join(" "))),
+            ("feedback" | "report", _) => Ok(Command::Feedback),
+            ("sub", [tags @ .., score]) if score.parse::<u64>().is_ok() && !tags.is_empty() => {
+                Ok(Command::Subscribe(to_vec(tags), score.parse().ok()))
+            }
+            ("sub", tags) if !tags.is_empty() => Ok(Command::Subscribe(to_vec(tags), None)),
+            ("unsub", tags) if !tags.is_empty() => Ok(Command::Unsubscribe(to_vec(tags))),
+            ("subs", _) => Ok(Command::Subscriptions),
+            ("leave", []) => Ok(Command::Leave(None)),
+            ("leave", [room]) => Ok(Command::Leave(Some(room.to_string()))),
+            ("blacklist" | "post" | "search" | "scan" | "sub" | "unsub" | "leave", _) => {
+                Err(anyhow::anyhow!("参数错误\n\n{HELP}"))
+            }
+            // 不认识的命令交给其他机器人处理
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    /// 订阅只影响自己，所有人都可以使用
+    pub fn requires_admin(&self) -> bool {
+        !matches!(
+            self,
+            Command::Help
+                | Command::Subscribe(..)
+                | Command::Unsubscribe(_)
+                | Command::Subscriptions
+        )
+    }
+
+    /// `user` 为发送命令的用户，Matrix 为用户 ID，VoceChat 为 UID
+    pub async fn execute(self, user: &str) -> Result<String> {
+        let reply = match self {
+            Command::Help => HELP.to_string(),
+            Command::Status => status()?,
+            Command::Pause => {
+                control::set_paused(true)?;
+                "已暂停定时扫描".to_string()
+            }
+            Command::Resume => {
+                control::set_paused(false)?;
+                "已恢复定时扫描".to_string()
+            }
+            Command::Scan => {
+                if control::is_scanning() {
+                    "正在扫描中".to_string()
+                } else {
+                    control::request_scan();
+                    "已开始扫描".to_string()
+                }
+            }
+            Command::Threshold(None) => format!("当前最低评分：{}", control::threshold()),
+            Command::Threshold(Some(score)) => {
+                control::set_threshold(score)?;
+                format!("最低评分已设置为 {}", score)
+            }
+            Command::Blacklist => {
+                let list = control::blacklist();
+                if list.is_empty() {
+                    "黑名单为空".to_string()
+                } else {
+                    format!("黑名单：{}", list.join(" "))
+                }
+            }
+            Command::BlacklistAdd(tag) => match control::blacklist_add(&tag)? {
+                true => format!("已添加 {}", tag),
+                false => format!("{} 已在黑名单中", tag),
+            },
+            Command::BlacklistRemove(tag) => match control::blacklist_remove(&tag)? {
+                true => format!("已移除 {}", tag),
+                false => format!("{} 不在黑名单中", tag),
+            },
+            Command::Post(id) => {
+                tokio::spawn(async move {
+                    crate::post(id)
+                        .await
+                        .unwrap_or_else(|e| log::error!("post {} failed: {}", id, e));
+                });
+                format!("正在发送 {}", id)
+            }
+            Command::Search(tags) => search(&tags).await?,
+            Command::Feedback => feedback::report(10),
+            // Matrix 在收到命令的地方处理，需要先回复再退出
+            Command::Leave(_) => "当前平台不支持退出".to_string(),
+            Command::Subscribe(tags, min_score) => {
+                subscription::subscribe(user, &tags, min_score)?;
+                match min_score {
+                    Some(score) => {
+                        format!("已订阅 {}，评分达到 {} 时私信通知", tags.join(" "), score)
+                    }
+                    None => format!("已订阅 {}，评分达到最低评分时私信通知", tags.join(" ")),
+                }
+            }
+            Command::Unsubscribe(tags) => match subscription::unsubscribe(user, &tags)? {
+                true => format!("已取消订阅 {}", tags.join(" ")),
+                false => format!("没有订阅 {}", tags.join(" ")),
+            },
+            Command::Subscriptions => {
+                let list = subscription::list(user)?;
+                if list.is_empty() {
+                    "没有订阅".to_string()
+                } else {
+                    let mut reply = "你的订阅：".to_string();
+                    for sub in list {
+                        reply.push_str(&format!("\n- {}", sub.tags.join(" ")));
+                        if let Some(score) = sub.min_score {
+                            reply.push_str(&format!("（{} 分以上）", score));
+                        }
+                    }
+                    reply
+                }
+            }
+        };
+        Ok(reply)
+    }
+}
+
+fn status() -> Result<String> {
+    let last_scan = match control::last_scan() {
+        0 => "尚未扫描".to_string(),
+        time => format!("{} 分钟前", (control::now() - time) / 60),
+    };
+    let delivered = yande::DB_HANDLE
+        .get_or_init(DB::init)
+        .recent(control::now().saturating_sub(60 * 60 * 24))?
+        .len();
+
+    Ok(format!(
+        "状态：{}\n上次扫描：{}\n最低评分：{}\n黑名单：{} 个标签\n24 小时内推送：{} 个帖子",
+        match (control::is_paused(), control::is_scanning()) {
+            (_, true) => "扫描中",
+            (true, false) => "已暂停",
+            (false, false) => "运行中",
+        },
+        last_scan,
+        control::threshold(),
+        control::blacklist().len(),
+        delivered
+    ))
+}
+
+fn to_vec(words: &[&str]) -> Vec<String> {
+    words.iter().map(|word| word.to_string()).collect()
+}
+
+async fn search(tags: &str) -> Result<String> {
+    let url = url::Url::parse_with_params("https://yande.re/post", &[("tags", tags)])?;
+    let html = yande::get(url.as_str()).await?;
+    let list = yande::get_image_list(&html)?;
+    if list.is_empty() {
+        return Ok(format!("没有找到 {} 相关的帖子", tags));
+    }
+
+    let mut reply = format!("{} 的搜索结果：", tags);
+    for id in list.iter().take(10) {
+        reply.push_str(&format!(
+            "\n- [https://yande.re/post/show/{id}](https://yande.re/post/show/{id})"
+        ));
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(Command::parse("hello").is_none());
+        assert!(Command::parse("!unknown").is_none());
+        assert_eq!(
+            Command::parse("!threshold 80").unwrap().unwrap(),
+            Command::Threshold(Some(80))
+        );
+        assert_eq!(
+            Command::parse("/blacklist add foo").unwrap().unwrap(),
+            Command::BlacklistAdd("foo".to_string())
+        );
+        assert_eq!(
+            Command::parse("!search a b").unwrap().unwrap(),
+            Command::Search("a b".to_string())
+        );
+        assert_eq!(Command::parse("!scan now").unwrap().unwrap(), Command::Scan);
+        assert_eq!(Command::parse("/stats").unwrap().unwrap(), Command::Status);
+        assert_eq!(
+            Command::parse("!sub a b 100").unwrap().unwrap(),
+            Command::Subscribe(vec!["a".to_string(), "b".to_string()], Some(100))
+        );
+        assert_eq!(
+            Command::parse("!sub a").unwrap().unwrap(),
+            Command::Subscribe(vec!["a".to_string()], None)
+        );
+        assert!(Command::parse("!sub").unwrap().is_err());
+        assert!(Command::parse("!post abc").unwrap().is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use clap::{error::ErrorKind, parser::ValueSource, CommandFactory, Parser};
+use serde_json::Value;
+
+use crate::{args, caption, Args};
+
+/// 检查配置文件是否修改的间隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 配置文件中的分组和可以写在其中的参数，按参数所在的结构体划分
+/// 不分组的键写在顶层
+const SECTIONS: [(&str, &[&str]); 10] = [
+    (
+        "matrix",
+        &["MatrixArgs", "AuthArgs", "KeysArgs", "SpoilerArgs"],
+    ),
+    ("voce", &["VoceArgs"]),
+    ("sources", &["SourceArgs"]),
+    ("schedule", &["ControlArgs", "RetentionArgs"]),
+    ("filters", &["ControlArgs"]),
+    ("routing", &["RouteArgs"]),
+    ("processing", &["ResizeArgs"]),
+    ("caption", &["CaptionArgs"]),
+    (
+        "sinks",
+        &["ArchiveArgs", "S3Args", "WebhookArgs", "EmailArgs"],
+    ),
+    ("web", &["WebArgs", "AdminArgs"]),
+];
+
+#[derive(clap::Args, Debug)]
+pub struct ConfigArgs {
+    /// 配置文件（.toml、.yaml 或 .yml），键名为参数名或环境变量名，可以写在顶层或对应的分组中
+    /// 命令行和环境变量优先于配置文件，修改后自动重新加载
+    #[arg(long, env = "CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+/// 解析命令行、环境变量和配置文件，优先级依次降低
+pub fn parse<T: Parser>() -> Result<T, clap::Error> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let matches = T::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)?;
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return T::try_parse_from(argv);
+    };
+
+    let file_args = file_args::<T>(path, &matches)
+        .map_err(|e| clap::Error::raw(ErrorKind::InvalidValue, format!("{e}\n")))?;
+    // 配置文件的值作为命令行参数插在最前面，子命令之前
+    let mut full = vec![argv[0].clone()];
+    full.extend(file_args.into_iter().map(OsString::from));
+    full.extend(argv.into_iter().skip(1));
+    T::try_parse_from(full)
+}
+
+/// 配置文件中没有被命令行和环境变量覆盖的值，转换为命令行参数
+fn file_args<T: CommandFactory>(
+    path: &Path,
+    matches: &clap::ArgMatches,
+) -> anyhow::Result<Vec<String>> {
+    let values = flatten(read(path)?);
+    let command = T::command();
+    let mut result = Vec::new();
+
+    for (key, value) in values {
+        let arg = command
+            .get_arguments()
+            .find(|arg| names(arg).contains(&normalize(&key)))
+            .ok_or_else(|| unknown_key(path, &key, &command))?;
+        check_section(&key, arg, &command)
+            .map_err(|e| anyhow::anyhow!("{e} in config file {}", path.display()))?;
+        let long = arg
+            .get_long()
+            .ok_or(anyhow::anyhow!("`{key}` can not be set in config file"))?;
+
+        let from_env = arg
+            .get_env()
+            .is_some_and(|env| std::env::var_os(env).is_some());
+        let from_cli =
+            matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+        if from_env || from_cli {
+            continue;
+        }
+
+        let delimiter = arg.get_value_delimiter().unwrap_or(',');
+        let value = match value {
+            Value::Array(items) => items
+                .iter()
+                .map(scalar)
+                .collect::<Option<Vec<_>>>()
+                .ok_or(anyhow::anyhow!("`{key}` should be a list of values"))?
+                .join(&delimiter.to_string()),
+            value => scalar(&value).ok_or(anyhow::anyhow!("`{key}` should be a value"))?,
+        };
+
+        // 开关类参数不带值
+        if !arg.get_action().takes_values() {
+            match value.as_str() {
+                "true" => result.push(format!("--{long}")),
+                "false" => {}
+                _ => anyhow::bail!("`{key}` should be true or false"),
+            }
+            continue;
+        }
+        result.push(format!("--{long}={value}"));
+    }
+    Ok(result)
+}
+
+/// 分组中的键必须属于该分组，分组只能有一层
+fn check_section(key: &str, arg: &clap::Arg, command: &clap::Command) -> anyhow::Result<()> {
+    let Some((section, _)) = key.rsplit_once('.') else {
+        return Ok(());
+    };
+    let Some((_, structs)) = SECTIONS
+        .iter()
+        .find(|(name, _)| *name == section.to_lowercase())
+    else {
+        let names: Vec<_> = SECTIONS.iter().map(|(name, _)| *name).collect();
+        anyhow::bail!(
+            "unknown section `{section}`, expected one of {}",
+            names.join(", ")
+        );
+    };
+
+    let belongs = |structs: &[&str]| {
+        command
+            .get_groups()
+            .filter(|group| structs.contains(&group.get_id().as_str()))
+            .any(|group| group.get_args().any(|id| id == arg.get_id()))
+    };
+    if belongs(structs) {
+        return Ok(());
+    }
+    let expected: Vec<_> = SECTIONS
+        .iter()
+        .filter(|(_, structs)| belongs(structs))
+        .map(|(name, _)| format!("[{name}]"))
+        .collect();
+    if expected.is_empty() {
+        anyhow::bail!("`{key}` should be at the top level");
+    }
+    anyhow::bail!(
+        "`{key}` does not belong to [{section}], move it to {} or the top level",
+        expected.join(" or ")
+    )
+}
+
+fn read(path: &Path) -> anyhow::Result<Value> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("read config file {}: {e}", path.display()))?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let value = match extension {
+        "toml" => serde_json::to_value(
+            toml::from_str::<toml::Value>(&text)
+                .map_err(|e| anyhow::anyhow!("invalid config file {}: {e}", path.display()))?,
+        )?,
+        "yaml" | "yml" => serde_yaml::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("invalid config file {}: {e}", path.display()))?,
+        _ => anyhow::bail!(
+            "unsupported config file {}, expected .toml, .yaml or .yml",
+            path.display()
+        ),
+    };
+    Ok(value)
+}
+
+/// 表只用于分组，取出所有叶子节点，键名带上分组便于报错
+fn flatten(value: Value) -> Vec<(String, Value)> {
+    fn walk(prefix: &str, value: Value, result: &mut Vec<(String, Value)>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let key = match prefix {
+                        "" => key,
+                        prefix => format!("{prefix}.{key}"),
+                    };
+                    walk(&key, value, result);
+                }
+            }
+            Value::Null => {}
+            value => result.push((prefix.to_string(), value)),
+        }
+    }
+    let mut result = Vec::new();
+    walk("", value, &mut result);
+    result
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// 分组中的键名，大小写和 `-`、`_` 不敏感
+fn normalize(key: &str) -> String {
+    key.rsplit('.')
+        .next()
+        .unwrap_or(key)
+        .to_lowercase()
+        .replace('-', "_")
+}
+
+fn names(arg: &clap::Arg) -> Vec<String> {
+    let mut names = vec![arg.get_id().as_str().to_string()];
+    names.extend(arg.get_long().map(normalize));
+    names.extend(arg.get_env().and_then(|env| env.to_str()).map(normalize));
+    names
+}
+
+fn unknown_key(path: &Path, key: &str, command: &clap::Command) -> anyhow::Error {
+    let key_name = normalize(key);
+    let suggestion = command
+        .get_arguments()
+        .flat_map(names)
+        .map(|name| (distance(&key_name, &name), name))
+        .filter(|(distance, _)| *distance <= 3)
+        .min();
+    match suggestion {
+        Some((_, name)) => anyhow::anyhow!(
+            "unknown key `{key}` in config file {}, did you mean `{name}`?",
+            path.display()
+        ),
+        None => anyhow::anyhow!("unknown key `{key}` in config file {}", path.display()),
+    }
+}
+
+/// 编辑距离，用于提示拼错的键名
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                previous.min(row[j]).min(current) + 1
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+/// 重新解析配置，失败时保留当前的配置
+/// 后端、登录信息、数据目录和监听地址只在启动时读取，重新加载时保留当前的值，保留后再检查
+pub fn reload() {
+    let result = parse::<Args>().and_then(|mut args| {
+        let ignored = args.keep_startup_only(crate::args());
+        args.validate().map(|args| (args, ignored))
+    });
+    match result {
+        Ok((args, ignored)) => {
+            if !ignored.is_empty() {
+                log::warn!("{} changed, restart to apply", ignored.join(", "));
+            }
+            crate::set_args(args);
+            caption::reset();
+            log::info!("config reloaded");
+        }
+        Err(e) => log::error!("reload config failed, keep current config: {}", e),
+    }
+}
+
+/// 配置文件修改或收到 SIGHUP 时重新加载
+pub fn watch() {
+    let Some(path) = args().config.config.clone() else {
+        return;
+    };
+    #[cfg(unix)]
+    tokio::spawn(on_hangup());
+    tokio::spawn(poll(path));
+}
+
+#[cfg(unix)]
+async fn on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("install SIGHUP handler failed: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        log::info!("SIGHUP received, reload config");
+        reload();
+    }
+}
+
+async fn poll(path: PathBuf) {
+    let modified = |path: &Path| -> Option<SystemTime> { fs::metadata(path).ok()?.modified().ok() };
+    let mut last = modified(&path);
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = modified(&path);
+        if current != last {
+            last = current;
+            log::info!("config file {} changed, reload", path.display());
+            reload();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let value: toml::Value = toml::from_str(
+            r#"
+            room_id = "!a:example.org"
+            [filters]
+            default-threshold = 100
+            blacklist = ["foo", "bar"]
+            "#,
+        )
+        .unwrap();
+        let values = flatten(serde_json::to_value(value).unwrap());
+        assert!(values.contains(&("room_id".to_string(), Value::from("!a:example.org"))));
+        assert!(values.contains(&("filters.default-threshold".to_string(), Value::from(100))));
+        assert_eq!(normalize("filters.default-threshold"), "default_threshold");
+        assert_eq!(normalize("ROUTES"), "routes");
+        assert_eq!(distance("treshold", "threshold"), 1);
+        assert_eq!(distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_check_section() {
+        let command = Args::command();
+        let arg = |id: &str| {
+            command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)
+                .unwrap()
+        };
+        assert!(check_section("archive_dir", arg("archive_dir"), &command).is_ok());
+        assert!(check_section("sinks.archive_dir", arg("archive_dir"), &command).is_ok());
+        assert!(check_section("Filters.blacklist", arg("blacklist"), &command).is_ok());
+        assert!(check_section("processing.archive_dir", arg("archive_dir"), &command).is_err());
+        assert!(check_section("sinks.s3.archive_dir", arg("archive_dir"), &command).is_err());
+        assert!(check_section("unknown.archive_dir", arg("archive_dir"), &command).is_err());
+        assert!(check_section("sinks.data_dir", arg("data_dir"), &command).is_err());
+        assert!(check_section("schedule.history_days", arg("history_days"), &command).is_ok());
+        assert!(check_section("processing.jpeg_quality", arg("jpeg_quality"), &command).is_ok());
+        #[cfg(feature = "matrix")]
+        assert!(check_section(
+            "matrix.session_passphrase",
+            arg("session_passphrase"),
+            &command
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::sync::Notify;
+
+use crate::{args, db::DB, yande::DB_HANDLE};
+
+#[derive(clap::Args, Debug)]
+pub struct ControlArgs {
+    /// 定时扫描的间隔（秒）
+    #[arg(long, env = "SCAN_INTERVAL", default_value = "3600")]
+    pub scan_interval: u64,
+
+    /// 默认的最低评分，用命令修改后以命令设置的为准，直到这里的值被修改
+    #[arg(long, env = "DEFAULT_THRESHOLD", default_value = "50")]
+    pub default_threshold: u64,
+
+    /// 固定的标签黑名单，逗号分隔，和命令添加的一起生效
+    #[arg(long, env = "BLACKLIST", value_delimiter = ',')]
+    pub blacklist: Vec<String>,
+}
+
+static PAUSED: OnceLock<AtomicBool> = OnceLock::new();
+static SCAN_NOW: OnceLock<Notify> = OnceLock::new();
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static LAST_SCAN: AtomicU64 = AtomicU64::new(0);
+static QUEUE: Mutex<Vec<i64>> = Mutex::new(Vec::new());
+
+fn db() -> &'static DB {
+    DB_HANDLE.get_or_init(DB::init)
+}
+
+fn paused() -> &'static AtomicBool {
+    PAUSED.get_or_init(|| {
+        AtomicBool::new(
+            db().get_value("paused")
+                .unwrap_or_else(|e| {
+                    log::error!("read paused state failed: {}", e);
+                    None
+                })
+                .unwrap_or(false),
+        )
+    })
+}
+
+pub fn is_paused() -> bool {
+    paused().load(Ordering::Relaxed)
+}
+
+/// 暂停后定时扫描不再执行，状态保存在数据库中，重启后保持
+pub fn set_paused(value: bool) -> Result<()> {
+    paused().store(value, Ordering::Relaxed);
+    db().set_value("paused", &value)
+}
+
+pub fn request_scan() {
+    SCAN_NOW.get_or_init(Notify::new).notify_one();
+}
+
+pub async fn scan_requested() {
+    SCAN_NOW.get_or_init(Notify::new).notified().await
+}
+
+pub fn is_scanning() -> bool {
+    SCANNING.load(Ordering::Relaxed)
+}
+
+pub fn scan_started() {
+    SCANNING.store(true, Ordering::Relaxed);
+}
+
+pub fn scan_finished() {
+    SCANNING.store(false, Ordering::Relaxed);
+    LAST_SCAN.store(now(), Ordering::Relaxed);
+}
+
+/// 上次扫描完成的时间，0 表示本次启动后还没有扫描过
+pub fn last_scan() -> u64 {
+    LAST_SCAN.load(Ordering::Relaxed)
+}
+
+pub fn scan_interval() -> Duration {
+    Duration::from_secs(args().control.scan_interval)
+}
+
+/// 本次扫描中等待发送的帖子
+pub fn queue() -> Vec<i64> {
+    QUEUE.lock().unwrap().clone()
+}
+
+pub fn enqueue(id: i64) {
+    QUEUE.lock().unwrap().push(id);
+}
+
+pub fn dequeue(id: i64) {
+    QUEUE.lock().unwrap().retain(|queued| *queued != id);
+}
+
+/// 用命令设置的最低评分，记下设置时配置中的默认值
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Threshold {
+    value: u64,
+    default: u64,
+}
+
+/// 配置中的 `default_threshold` 修改后，之前用命令设置的值不再生效
+pub fn threshold() -> u64 {
+    let default = args().control.default_threshold;
+    match db().get_value::<Threshold>("threshold_override") {
+        Ok(Some(threshold)) if threshold.default == default => threshold.value,
+        Ok(_) => default,
+        Err(e) => {
+            log::error!("read threshold failed: {}", e);
+            default
+        }
+    }
+}
+
+pub fn set_threshold(value: u64) -> Result<()> {
+    let default = args().control.default_threshold;
+    db().set_value("threshold_override", &Threshold { value, default })
+}
+
+pub fn blacklist() -> Vec<String> {
+    db().get_value("blacklist")
+        .unwrap_or_else(|e| {
+            log::error!("read blacklist failed: {}", e);
+            None
+        })
+        .unwrap_or_default()
+}
+
+pub fn set_blacklist(list: &[String]) -> Result<()> {
+    db().set_value("blacklist", &list)
+}
+
+/// 返回是否有变化
+pub fn blacklist_add(tag: &str) -> Result<bool> {
+    let mut list = blacklist();
+    if list.iter().any(|t| t == tag) {
+        return Ok(false);
+    }
+    list.push(tag.to_string());
+    db().set_value("blacklist", &list)?;
+    Ok(true)
+}
+
+/// 返回是否有变化
+pub fn blacklist_remove(tag: &str) -> Result<bool> {
+    let mut list = blacklist();
+    let len = list.len();
+    list.retain(|t| t != tag);
+    db().set_value("blacklist", &list)?;
+    Ok(list.len() != len)
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -1,15 +1,122 @@
 use sled::Db;
 
-use crate::args;
+use crate::{
+    args, control,
+    yande::{ImgData, Post},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct RetentionArgs {
+    /// 已推送帖子的历史记录保留天数
+    #[arg(long, env = "HISTORY_DAYS", default_value = "30")]
+    pub history_days: u64,
+
+    /// 已扫描过的帖子在这段时间（天）内不会重复处理
+    #[arg(long, env = "SEEN_DAYS", default_value = "7")]
+    pub seen_days: u64,
+}
 
 #[derive(Debug)]
 pub struct DB(Db);
 
+/// 一条推送记录，`posts` 为整个家族的帖子信息
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct History {
+    pub id: i64,
+    pub score: u64,
+    pub delivered_at: u64,
+    pub posts: Vec<Post>,
+}
+
+impl History {
+    pub fn new(id: i64, img_data: &ImgData) -> Self {
+        History {
+            id,
+            score: img_data.score,
+            delivered_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            posts: img_data
+                .url
+                .iter()
+                .filter_map(|(id, _)| img_data.posts.get(id).cloned())
+                .collect(),
+        }
+    }
+
+    pub fn root(&self) -> Option<&Post> {
+        self.posts
+            .iter()
+            .find(|post| post.id == self.id)
+            .or(self.posts.first())
+    }
+}
+
+/// 机器人发出的消息对应的帖子，用于把回应记到帖子上
+/// `sent_at` 用于按 HISTORY_DAYS 清理，旧版本的记录没有这个字段，会在下次清理时删除
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Message {
+    pub id: i64,
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub sent_at: u64,
+}
+
+/// 同一用户对同一帖子的回应，`events` 为可以撤回的回应事件
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Reaction {
+    message: String,
+    events: Vec<String>,
+    reacted_at: u64,
+}
+
+/// 某个标签被推送的次数和收到的点赞数
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TagStat {
+    pub delivered: u64,
+    pub likes: u64,
+}
+
+/// 用户订阅，帖子包含所有标签且评分不低于 `min_score` 时私信通知
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Subscription {
+    pub user: String,
+    pub tags: Vec<String>,
+    pub min_score: Option<u64>,
+}
+
+impl Subscription {
+    fn key(&self) -> String {
+        format!("{}\n{}", self.user, self.tags.join(" "))
+    }
+}
+
 impl DB {
+    /// 数据库同时只能被一个进程打开，被运行中的服务锁定时提示先停止服务
+    pub fn open() -> anyhow::Result<Self> {
+        Self::open_path(&std::path::Path::new(&args().data_dir).join("db"))
+    }
+
+    pub fn open_path(path: &std::path::Path) -> anyhow::Result<Self> {
+        match sled::open(path) {
+            Ok(db) => Ok(DB(db)),
+            Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => {
+                Err(anyhow::anyhow!(
+                    "database {} is locked by another process, stop the running service first",
+                    path.display()
+                ))
+            }
+            Err(e) => Err(anyhow::anyhow!(
+                "open database {} failed: {e}",
+                path.display()
+            )),
+        }
+    }
+
+    /// 启动时已经用 `open` 打开过，这里只在没有提前打开时使用
     pub fn init() -> Self {
-        let path = std::path::Path::new(&args().data_dir).join("db");
-        let db = sled::open(path).unwrap();
-        DB(db)
+        Self::open().unwrap_or_else(|e| panic!("{e}"))
     }
 
     pub fn insert(&self, key: &str) -> sled::Result<()> {
@@ -25,6 +132,242 @@ impl DB {
         self.0.contains_key(key)
     }
 
+    /// 读取 meta 中的设置或状态
+    pub fn get_value<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<T>> {
+        let tree = self.0.open_tree("meta")?;
+        match tree.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_value<T: serde::Serialize>(&self, key: &str, value: &T) -> anyhow::Result<()> {
+        let tree = self.0.open_tree("meta")?;
+        tree.insert(key, serde_json::to_vec(value)?)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// 历史记录按推送时间排序，key 为时间戳和帖子 ID
+    pub fn record(&self, history: &History) -> anyhow::Result<()> {
+        let tree = self.0.open_tree("history")?;
+        let mut key = history.delivered_at.to_be_bytes().to_vec();
+        key.extend_from_slice(&history.id.to_be_bytes());
+        tree.insert(key, serde_json::to_vec(history)?)?;
+        Ok(())
+    }
+
+    /// 最近的推送记录，新的在前
+    pub fn recent(&self, since: u64) -> anyhow::Result<Vec<History>> {
+        let tree = self.0.open_tree("history")?;
+        let mut result = Vec::new();
+        for item in tree.range(since.to_be_bytes()..).rev() {
+            let (_, value) = item?;
+            result.push(serde_json::from_slice(&value)?);
+        }
+        Ok(result)
+    }
+
+    /// 从新到旧查找符合条件的推送记录，找到 `limit` 条后停止
+    pub fn find_recent(
+        &self,
+        limit: usize,
+        filter: impl Fn(&History) -> bool,
+    ) -> anyhow::Result<Vec<History>> {
+        let tree = self.0.open_tree("history")?;
+        let mut result = Vec::new();
+        for item in tree.iter().rev() {
+            if result.len() >= limit {
+                break;
+            }
+            let (_, value) = item?;
+            let history: History = serde_json::from_slice(&value)?;
+            if filter(&history) {
+                result.push(history);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn set_message(&self, key: &str, message: &Message) -> anyhow::Result<()> {
+        let tree = self.0.open_tree("message")?;
+        tree.insert(key, serde_json::to_vec(message)?)?;
+        Ok(())
+    }
+
+    pub fn message(&self, key: &str) -> anyhow::Result<Option<Message>> {
+        let tree = self.0.open_tree("message")?;
+        match tree.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 同一用户对同一帖子只记一次，返回是否是新的回应
+    /// `event` 为回应事件的 ID，撤回时用 `remove_reaction` 找到对应的回应
+    pub fn add_reaction(
+        &self,
+        id: i64,
+        user: &str,
+        message: &str,
+        event: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let tree = self.0.open_tree("reaction")?;
+        let key = format!("{id}:{user}");
+        let old = tree.get(&key)?;
+        let mut reaction: Reaction = match &old {
+            Some(value) => serde_json::from_slice(value).unwrap_or_default(),
+            None => Reaction {
+                message: message.to_string(),
+                events: Vec::new(),
+                reacted_at: control::now(),
+            },
+        };
+        if let Some(event) = event {
+            reaction.events.push(event.to_string());
+            self.0
+                .open_tree("reaction_event")?
+                .insert(event, key.as_bytes())?;
+        }
+        tree.insert(&key, serde_json::to_vec(&reaction)?)?;
+        Ok(old.is_none())
+    }
+
+    /// 撤回一个回应事件，同一用户对该帖子的回应全部撤回后返回被回应的消息
+    #[cfg_attr(not(feature = "matrix"), allow(dead_code))]
+    pub fn remove_reaction(&self, event: &str) -> anyhow::Result<Option<String>> {
+        let Some(key) = self.0.open_tree("reaction_event")?.remove(event)? else {
+            return Ok(None);
+        };
+        let tree = self.0.open_tree("reaction")?;
+        let Some(value) = tree.get(&key)? else {
+            return Ok(None);
+        };
+        let mut reaction: Reaction = serde_json::from_slice(&value)?;
+        reaction.events.retain(|e| e != event);
+        if !reaction.events.is_empty() {
+            tree.insert(&key, serde_json::to_vec(&reaction)?)?;
+            return Ok(None);
+        }
+        tree.remove(&key)?;
+        Ok(Some(reaction.message))
+    }
+
+    pub fn update_tag_stat(&self, tag: &str, f: impl Fn(&mut TagStat)) -> anyhow::Result<()> {
+        let tree = self.0.open_tree("tag_stat")?;
+        tree.fetch_and_update(tag, |value| {
+            let mut stat: TagStat = value
+                .and_then(|value| serde_json::from_slice(value).ok())
+                .unwrap_or_default();
+            f(&mut stat);
+            serde_json::to_vec(&stat).ok()
+        })?;
+        Ok(())
+    }
+
+    pub fn tag_stats(&self) -> anyhow::Result<Vec<(String, TagStat)>> {
+        let tree = self.0.open_tree("tag_stat")?;
+        let mut result = Vec::new();
+        for item in tree.iter() {
+            let (key, value) = item?;
+            result.push((
+                String::from_utf8_lossy(&key).to_string(),
+                serde_json::from_slice(&value)?,
+            ));
+        }
+        Ok(result)
+    }
+
+    /// 相同标签的订阅会被覆盖
+    pub fn subscribe(&self, subscription: &Subscription) -> anyhow::Result<()> {
+        let tree = self.0.open_tree("subscription")?;
+        tree.insert(subscription.key(), serde_json::to_vec(subscription)?)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, subscription: &Subscription) -> anyhow::Result<bool> {
+        let tree = self.0.open_tree("subscription")?;
+        let removed = tree.remove(subscription.key())?.is_some();
+        tree.flush()?;
+        Ok(removed)
+    }
+
+    /// `user` 为 `None` 时返回所有人的订阅
+    pub fn subscriptions(&self, user: Option<&str>) -> anyhow::Result<Vec<Subscription>> {
+        let tree = self.0.open_tree("subscription")?;
+        let prefix = user.map(|user| format!("{user}\n")).unwrap_or_default();
+        let mut result = Vec::new();
+        for item in tree.scan_prefix(prefix) {
+            let (_, value) = item?;
+            result.push(serde_json::from_slice(&value)?);
+        }
+        Ok(result)
+    }
+
+    /// 每个帖子只通知同一用户一次，返回是否是第一次
+    pub fn mark_notified(&self, user: &str, id: i64) -> sled::Result<bool> {
+        let tree = self.0.open_tree("notified")?;
+        Ok(tree
+            .insert(format!("{user}:{id}"), &control::now().to_be_bytes())?
+            .is_none())
+    }
+
+    /// 通知发送失败时撤销标记，下次还可以通知
+    pub fn unmark_notified(&self, user: &str, id: i64) -> sled::Result<()> {
+        let tree = self.0.open_tree("notified")?;
+        tree.remove(format!("{user}:{id}"))?;
+        Ok(())
+    }
+
+    /// 每个表的记录数，默认表为扫描过的帖子
+    pub fn stats(&self) -> sled::Result<Vec<(String, usize)>> {
+        let mut result = Vec::new();
+        for name in self.0.tree_names() {
+            let tree = self.0.open_tree(&name)?;
+            let name = match name.as_ref() {
+                b"__sled__default" => "seen".to_string(),
+                name => String::from_utf8_lossy(name).to_string(),
+            };
+            result.push((name, tree.len()));
+        }
+        Ok(result)
+    }
+
+    /// 删除帖子的扫描和推送记录，之后可以再次推送
+    /// 推送记录中同一家族的帖子一起删除，返回删除了扫描记录的帖子
+    pub fn forget(&self, id: i64) -> anyhow::Result<Vec<i64>> {
+        let history = self.0.open_tree("history")?;
+        let mut ids = vec![id];
+        for item in history.iter() {
+            let (key, value) = item?;
+            let record: History = serde_json::from_slice(&value)?;
+            if record.id == id || record.posts.iter().any(|post| post.id == id) {
+                ids.push(record.id);
+                ids.extend(record.posts.iter().map(|post| post.id));
+                history.remove(key)?;
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut forgotten = Vec::new();
+        for id in ids {
+            if self.0.remove(id.to_string())?.is_some() {
+                forgotten.push(id);
+            }
+        }
+        self.0.flush()?;
+        Ok(forgotten)
+    }
+
+    pub fn size_on_disk(&self) -> sled::Result<u64> {
+        self.0.size_on_disk()
+    }
+
     pub fn auto_remove(&self) -> sled::Result<()> {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -35,14 +378,60 @@ impl DB {
             let key = key?;
             let value = self.0.get(&key)?.unwrap();
             let value = u64::from_be_bytes(value.as_ref().try_into().unwrap());
-            if timestamp - value > 60 * 60 * 24 * 7 {
+            if timestamp - value > 60 * 60 * 24 * args().retention.seen_days {
                 keys.push(key);
             }
         }
         for key in keys {
             self.0.remove(key)?;
         }
+
+        let history = self.0.open_tree("history")?;
+        let expired = timestamp.saturating_sub(60 * 60 * 24 * args().retention.history_days);
+        for key in history.range(..expired.to_be_bytes()).keys() {
+            history.remove(key?)?;
+        }
+
+        // 消息、回应和通知记录跟随推送记录一起过期
+        self.prune("message", expired, |value| {
+            serde_json::from_slice::<Message>(value)
+                .ok()
+                .map(|message| message.sent_at)
+        })?;
+        self.prune("reaction", expired, |value| {
+            serde_json::from_slice::<Reaction>(value)
+                .ok()
+                .map(|reaction| reaction.reacted_at)
+        })?;
+        let reaction = self.0.open_tree("reaction")?;
+        let reaction_event = self.0.open_tree("reaction_event")?;
+        for item in reaction_event.iter() {
+            let (event, key) = item?;
+            if !reaction.contains_key(key)? {
+                reaction_event.remove(event)?;
+            }
+        }
+        self.prune("notified", expired, |value| {
+            value.try_into().ok().map(u64::from_be_bytes)
+        })?;
         self.0.flush()?;
         Ok(())
     }
+
+    /// 删除时间早于 `expired` 的记录，取不到时间的旧记录也一起删除
+    fn prune(
+        &self,
+        name: &str,
+        expired: u64,
+        time: impl Fn(&[u8]) -> Option<u64>,
+    ) -> sled::Result<()> {
+        let tree = self.0.open_tree(name)?;
+        for item in tree.iter() {
+            let (key, value) = item?;
+            if !matches!(time(&value), Some(time) if time >= expired) {
+                tree.remove(key)?;
+            }
+        }
+        Ok(())
+    }
 }
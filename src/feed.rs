@@ -0,0 +1,315 @@
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::json;
+
+use crate::{
+    db::{History, DB},
+    web::public_url,
+    yande::DB_HANDLE,
+};
+
+const TITLE: &str = "Yande.re Popular";
+const HOME_URL: &str = "https://yande.re/post/popular_recent";
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct FeedQuery {
+    /// 逗号分隔的分级，例如 s,q
+    rating: Option<String>,
+    /// 逗号或空格分隔，需全部包含
+    tags: Option<String>,
+    min_score: Option<u64>,
+    limit: Option<usize>,
+}
+
+impl FeedQuery {
+    fn matches(&self, history: &History) -> bool {
+        if self.min_score.is_some_and(|min| history.score < min) {
+            return false;
+        }
+        let Some(post) = history.root() else {
+            return self.rating.is_none() && self.tags.is_none();
+        };
+        if let Some(rating) = &self.rating {
+            if !rating.split(',').any(|r| r.trim() == post.rating) {
+                return false;
+            }
+        }
+        if let Some(tags) = &self.tags {
+            let post_tags = post.tag_list();
+            if !tags
+                .split([',', ' '])
+                .filter(|tag| !tag.is_empty())
+                .all(|tag| post_tags.contains(&tag))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/feed/rss", get(rss))
+        .route("/feed/atom", get(atom))
+        .route("/feed/json", get(json_feed))
+}
+
+/// 读取和反序列化历史记录是阻塞操作，放到单独的线程
+async fn items(query: FeedQuery) -> Result<Vec<History>, StatusCode> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    tokio::task::spawn_blocking(move || {
+        DB_HANDLE
+            .get_or_init(DB::init)
+            .find_recent(limit, |history| query.matches(history))
+    })
+    .await
+    .map_err(|e| {
+        log::error!("read history task failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .map_err(|e| {
+        log::error!("read history failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+struct Item {
+    id: i64,
+    title: String,
+    link: String,
+    html: String,
+    image: Option<String>,
+    author: Option<String>,
+    tags: Vec<String>,
+    date: DateTime<Utc>,
+}
+
+impl From<&History> for Item {
+    fn from(history: &History) -> Self {
+        let post = history.root();
+        let link = format!("https://yande.re/post/show/{}", history.id);
+        let author = post.and_then(|post| post.artist()).map(str::to_string);
+        let title = match &author {
+            Some(artist) => format!("#{} by {}", history.id, artist),
+            None => format!("#{}", history.id),
+        };
+        let image = post
+            .map(|post| post.preview_url.clone())
+            .filter(|url| !url.is_empty());
+
+        let mut html = String::new();
+        for child in &history.posts {
+            if !child.preview_url.is_empty() {
+                html.push_str(&format!(
+                    r#"<p><a href="https://yande.re/post/show/{}"><img src="{}" /></a></p>"#,
+                    child.id,
+                    escape(&child.preview_url)
+                ));
+            }
+        }
+        html.push_str(&format!(
+            r#"<p>Score: {} · <a href="{}">{}</a></p>"#,
+            history.score, link, link
+        ));
+
+        Item {
+            id: history.id,
+            title,
+            link,
+            html,
+            image,
+            author,
+            tags: post
+                .map(|post| post.tag_list().iter().map(|t| t.to_string()).collect())
+                .unwrap_or_default(),
+            date: Utc
+                .timestamp_opt(history.delivered_at as i64, 0)
+                .single()
+                .unwrap_or_else(Utc::now),
+        }
+    }
+}
+
+async fn rss(Query(query): Query<FeedQuery>) -> Result<impl IntoResponse, StatusCode> {
+    let items: Vec<Item> = items(query).await?.iter().map(Item::from).collect();
+
+    let mut body = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    body.push_str(r#"<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/" xmlns:atom="http://www.w3.org/2005/Atom"><channel>"#);
+    // 频道的 link 是对应的网页，订阅源自身的地址放在 atom:link 中
+    body.push_str(&format!(
+        r#"<title>{TITLE}</title><link>{HOME_URL}</link><description>{TITLE}</description><atom:link href="{}/feed/rss" rel="self" type="application/rss+xml" />"#,
+        escape(&public_url())
+    ));
+    for item in items {
+        body.push_str("<item>");
+        body.push_str(&format!(
+            "<title>{}</title><link>{}</link><guid isPermaLink=\"true\">{}</guid><pubDate>{}</pubDate>",
+            escape(&item.title),
+            item.link,
+            item.link,
+            item.date.to_rfc2822()
+        ));
+        body.push_str(&format!(
+            "<description>{}</description>",
+            escape(&item.html)
+        ));
+        if let Some(image) = &item.image {
+            body.push_str(&format!(r#"<media:thumbnail url="{}" />"#, escape(image)));
+        }
+        for tag in &item.tags {
+            body.push_str(&format!("<category>{}</category>", escape(tag)));
+        }
+        body.push_str("</item>");
+    }
+    body.push_str("</channel></rss>");
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        body,
+    ))
+}
+
+async fn atom(Query(query): Query<FeedQuery>) -> Result<impl IntoResponse, StatusCode> {
+    let items: Vec<Item> = items(query).await?.iter().map(Item::from).collect();
+    let updated = items.first().map(|item| item.date).unwrap_or_else(Utc::now);
+    let self_url = format!("{}/feed/atom", public_url());
+
+    let mut body = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    body.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    // Atom 要求每个条目都有作者，没有画师标签的条目使用订阅源的作者
+    body.push_str(&format!(
+        r#"<title>{TITLE}</title><id>{}</id><link rel="self" href="{}" /><link href="{HOME_URL}" /><updated>{}</updated><author><name>yande.re</name><uri>https://yande.re/</uri></author>"#,
+        escape(&self_url),
+        escape(&self_url),
+        updated.to_rfc3339()
+    ));
+    for item in items {
+        body.push_str("<entry>");
+        body.push_str(&format!(
+            r#"<title>{}</title><id>{}</id><link href="{}" /><updated>{}</updated>"#,
+            escape(&item.title),
+            item.link,
+            item.link,
+            item.date.to_rfc3339()
+        ));
+        if let Some(author) = &item.author {
+            body.push_str(&format!("<author><name>{}</name></author>", escape(author)));
+        }
+        body.push_str(&format!(
+            r#"<content type="html">{}</content>"#,
+            escape(&item.html)
+        ));
+        for tag in &item.tags {
+            body.push_str(&format!(r#"<category term="{}" />"#, escape(tag)));
+        }
+        body.push_str("</entry>");
+    }
+    body.push_str("</feed>");
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        body,
+    ))
+}
+
+async fn json_feed(Query(query): Query<FeedQuery>) -> Result<impl IntoResponse, StatusCode> {
+    let items: Vec<_> = items(query)
+        .await?
+        .iter()
+        .map(Item::from)
+        .map(|item| {
+            json!({
+                "id": item.id.to_string(),
+                "url": item.link,
+                "title": item.title,
+                "content_html": item.html,
+                "image": item.image,
+                "date_published": item.date.to_rfc3339(),
+                "tags": item.tags,
+            })
+        })
+        .collect();
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/feed+json; charset=utf-8")],
+        json!({
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": TITLE,
+            "home_page_url": HOME_URL,
+            "feed_url": format!("{}/feed/json", public_url()),
+            "items": items,
+        })
+        .to_string(),
+    ))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yande::Post;
+
+    fn history(score: u64, rating: &str, tags: &str) -> History {
+        History {
+            id: 1,
+            score,
+            delivered_at: 0,
+            posts: vec![Post {
+                id: 1,
+                rating: rating.to_string(),
+                tags: tags.to_string(),
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let history = history(100, "s", "foo bar");
+        assert!(FeedQuery::default().matches(&history));
+
+        let query = |rating: Option<&str>, tags: Option<&str>, min_score: Option<u64>| FeedQuery {
+            rating: rating.map(str::to_string),
+            tags: tags.map(str::to_string),
+            min_score,
+            limit: None,
+        };
+        assert!(query(Some("s,q"), None, None).matches(&history));
+        assert!(!query(Some("e"), None, None).matches(&history));
+        assert!(query(None, Some("foo,bar"), None).matches(&history));
+        assert!(!query(None, Some("foo baz"), None).matches(&history));
+        assert!(query(None, None, Some(100)).matches(&history));
+        assert!(!query(None, None, Some(101)).matches(&history));
+
+        let empty = History {
+            posts: Vec::new(),
+            ..history
+        };
+        assert!(FeedQuery::default().matches(&empty));
+        assert!(!query(Some("s"), None, None).matches(&empty));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x?a=1&b=2">"#),
+            "&lt;a href=&quot;x?a=1&amp;b=2&quot;&gt;"
+        );
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+    }
+}
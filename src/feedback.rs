@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{
+    control,
+    db::{Message, TagStat, DB},
+    yande::{ImgData, DB_HANDLE},
+};
+
+/// 所有推送的合计，空字符串不会是标签
+const TOTAL: &str = "";
+/// 平滑时相当于先验的推送次数，推送少的标签更接近整体水平
+const PRIOR: f64 = 5.0;
+/// 评分调整倍数的范围
+const MIN_FACTOR: f64 = 0.5;
+const MAX_FACTOR: f64 = 2.0;
+/// 记为点赞的回应，比较时忽略表情的变体选择符
+const LIKE_KEYS: [&str; 9] = ["👍", "❤", "😍", "🥰", "💯", "🔥", "⭐", "🌟", "👏"];
+
+fn db() -> &'static DB {
+    DB_HANDLE.get_or_init(DB::init)
+}
+
+fn tags_of(id: i64, img_data: &ImgData) -> Vec<String> {
+    img_data
+        .posts
+        .get(&id)
+        .map(|post| post.tag_list().iter().map(|tag| tag.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// 记录一次推送及其发出的消息，`messages` 为各发送端的消息 ID
+pub fn record_delivery(id: i64, img_data: &ImgData, messages: &[String]) -> Result<()> {
+    let tags = tags_of(id, img_data);
+    for tag in tags.iter().map(String::as_str).chain([TOTAL]) {
+        db().update_tag_stat(tag, |stat| stat.delivered += 1)?;
+    }
+
+    let message = Message {
+        id,
+        tags,
+        sent_at: control::now(),
+    };
+    for key in messages {
+        db().set_message(key, &message)?;
+    }
+    Ok(())
+}
+
+pub fn is_like(key: &str) -> bool {
+    let key = key.trim_end_matches('\u{fe0f}');
+    LIKE_KEYS.contains(&key)
+}
+
+/// 记录用户对某条消息的回应，返回是否计入
+/// `event` 为回应事件的 ID，撤回时传给 `remove_reaction`
+pub fn record_reaction(message_key: &str, user: &str, event: Option<&str>) -> Result<bool> {
+    let Some(message) = db().message(message_key)? else {
+        return Ok(false);
+    };
+    if !db().add_reaction(message.id, user, message_key, event)? {
+        return Ok(false);
+    }
+
+    log::info!("{} liked {}", user, message.id);
+    for tag in message.tags.iter().map(String::as_str).chain([TOTAL]) {
+        db().update_tag_stat(tag, |stat| stat.likes += 1)?;
+    }
+    Ok(true)
+}
+
+/// 撤回回应，用户对该帖子的回应全部撤回后不再计入点赞
+#[cfg_attr(not(feature = "matrix"), allow(dead_code))]
+pub fn remove_reaction(event: &str) -> Result<()> {
+    let Some(message_key) = db().remove_reaction(event)? else {
+        return Ok(());
+    };
+    let Some(message) = db().message(&message_key)? else {
+        return Ok(());
+    };
+
+    log::info!("like of {} redacted", message.id);
+    for tag in message.tags.iter().map(String::as_str).chain([TOTAL]) {
+        db().update_tag_stat(tag, |stat| stat.likes = stat.likes.saturating_sub(1))?;
+    }
+    Ok(())
+}
+
+/// 每次扫描前读取一次，避免逐个候选查询数据库
+#[derive(Default)]
+pub struct Feedback {
+    total: TagStat,
+    tags: HashMap<String, TagStat>,
+}
+
+impl Feedback {
+    pub fn load() -> Self {
+        let mut tags: HashMap<_, _> = db()
+            .tag_stats()
+            .unwrap_or_else(|e| {
+                log::error!("read feedback failed: {}", e);
+                Vec::new()
+            })
+            .into_iter()
+            .collect();
+        let total = tags.remove(TOTAL).unwrap_or_default();
+        Feedback { total, tags }
+    }
+
+    fn rate(&self) -> f64 {
+        self.total.likes as f64 / self.total.delivered.max(1) as f64
+    }
+
+    /// 平滑后的点赞率，推送次数越多越接近实际点赞率
+    fn tag_rate(&self, stat: &TagStat) -> f64 {
+        (stat.likes as f64 + PRIOR * self.rate()) / (stat.delivered as f64 + PRIOR)
+    }
+
+    /// 根据帖子标签以往的点赞率计算评分倍数，没有数据时为 1
+    pub fn factor<'a>(&self, tags: impl IntoIterator<Item = &'a str>) -> f64 {
+        let rate = self.rate();
+        if rate == 0.0 {
+            return 1.0;
+        }
+        let rates: Vec<f64> = tags
+            .into_iter()
+            .filter_map(|tag| self.tags.get(tag))
+            .map(|stat| self.tag_rate(stat) / rate)
+            .collect();
+        if rates.is_empty() {
+            return 1.0;
+        }
+        (rates.iter().sum::<f64>() / rates.len() as f64).clamp(MIN_FACTOR, MAX_FACTOR)
+    }
+
+    /// 按点赞率排序的标签，只统计推送过 `min_delivered` 次以上的
+    fn ranking(&self, min_delivered: u64) -> Vec<(&str, f64, &TagStat)> {
+        let mut list: Vec<_> = self
+            .tags
+            .iter()
+            .filter(|(_, stat)| stat.delivered >= min_delivered)
+            .map(|(tag, stat)| (tag.as_str(), self.tag_rate(stat), stat))
+            .collect();
+        list.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+        list
+    }
+}
+
+pub fn report(top: usize) -> String {
+    let feedback = Feedback::load();
+    if feedback.total.likes == 0 {
+        return "还没有收到任何回应".to_string();
+    }
+
+    let ranking = feedback.ranking(3);
+    let line = |(tag, _, stat): &(&str, f64, &TagStat)| {
+        format!("\n- {}：{}/{}", tag, stat.likes, stat.delivered)
+    };
+    let mut reply = format!(
+        "共推送 {} 个帖子，收到 {} 个赞\n\n最受欢迎：",
+        feedback.total.delivered, feedback.total.likes
+    );
+    reply.extend(ranking.iter().take(top).map(line));
+    reply.push_str("\n\n最不受欢迎：");
+    reply.extend(ranking.iter().rev().take(top).map(line));
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_factor() {
+        let stat = |delivered, likes| TagStat { delivered, likes };
+        let feedback = Feedback {
+            total: stat(100, 20),
+            tags: HashMap::from([
+                ("liked".to_string(), stat(20, 16)),
+                ("ignored".to_string(), stat(20, 0)),
+            ]),
+        };
+
+        assert_eq!(feedback.factor(["unknown"]), 1.0);
+        assert_eq!(feedback.factor(["liked"]), MAX_FACTOR);
+        assert!((feedback.factor(["ignored"]) - MIN_FACTOR).abs() < f64::EPSILON);
+        let mixed = feedback.factor(["liked", "ignored", "unknown"]);
+        assert!(mixed > 1.0 && mixed < MAX_FACTOR);
+
+        let empty = Feedback {
+            total: TagStat::default(),
+            tags: HashMap::new(),
+        };
+        assert_eq!(empty.factor(["liked"]), 1.0);
+    }
+
+    #[test]
+    fn test_is_like() {
+        assert!(is_like("👍"));
+        assert!(is_like("👍\u{fe0f}"));
+        assert!(is_like("❤️"));
+        assert!(!is_like("👎"));
+        assert!(!is_like("😡"));
+    }
+}
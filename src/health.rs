@@ -0,0 +1,69 @@
+use std::{net::SocketAddr, sync::OnceLock, time::Instant};
+
+use axum::{http::StatusCode, routing::get, Json, Router};
+use serde_json::{json, Value};
+
+use crate::{args, bot, control, db::DB, yande::DB_HANDLE};
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// 启动时调用，还没有扫描过时从启动时间开始计算
+pub fn init() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// 数据库可用、机器人已登录并在线、最近的扫描没有超过两个周期
+async fn readyz() -> (StatusCode, Json<Value>) {
+    let db = DB_HANDLE
+        .get_or_init(DB::init)
+        .size_on_disk()
+        .map_err(|e| log::error!("readiness db check failed: {}", e))
+        .is_ok();
+    let bot = bot::is_ready();
+    let scan = control::is_paused() || scan_recent();
+
+    let status = if db && bot && scan {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(json!({ "db": db, "bot": bot, "scan": scan })))
+}
+
+fn scan_recent() -> bool {
+    let window = control::scan_interval() * 2;
+    match control::last_scan() {
+        0 => STARTED.get_or_init(Instant::now).elapsed() < window,
+        time => control::now().saturating_sub(time) < window.as_secs(),
+    }
+}
+
+/// `healthcheck` 子命令，请求本机管理接口的 /readyz，就绪时返回 0
+pub async fn check() -> i32 {
+    let mut addr = args().admin.admin_addr;
+    if addr.ip().is_unspecified() {
+        addr = SocketAddr::from(([127, 0, 0, 1], addr.port()));
+    }
+    let url = format!("http://{addr}/readyz");
+    match reqwest::get(&url).await {
+        Ok(resp) if resp.status().is_success() => 0,
+        Ok(resp) => {
+            eprintln!("not ready: {}", resp.text().await.unwrap_or_default());
+            1
+        }
+        Err(e) => {
+            eprintln!("healthcheck failed: {}", e);
+            1
+        }
+    }
+}
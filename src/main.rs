@@ -2,7 +2,7 @@ use std::{
     fs::create_dir_all,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, OnceLock,
+        Arc, OnceLock, RwLock,
     },
 };
 
@@ -12,129 +12,296 @@ use tokio::sync::Semaphore;
 use clap::Parser;
 use yande::DB_HANDLE;
 
+mod admin;
 mod bot;
+mod caption;
+mod cli;
+mod command;
+mod config;
+mod control;
 mod db;
+mod feed;
+mod feedback;
+mod health;
+mod metrics;
+mod preview;
 mod resize;
+mod route;
+mod sink;
+mod subscription;
+mod web;
 mod yande;
 
-#[cfg(feature = "voce")]
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// 发送到的频道ID
-    #[arg(short, long, env = "CHANNEL_ID")]
-    channel_id: String,
+    /// 使用的机器人后端
+    #[arg(long, env = "BACKEND", value_enum, default_value_t)]
+    backend: bot::Backend,
 
-    /// 机器人API_KEY
-    #[arg(short, long, env = "API_KEY")]
-    api_key: String,
-
-    /// 服务器域名
-    #[arg(short, long, env = "SERVER_DOMAIN")]
-    server_domain: String,
-
-    /// 服务存储临时文件的目录
+    /// 服务存储文件的目录
     /// 默认为data
     #[arg(short, long, default_value = "data", env = "DATA_DIR")]
     data_dir: String,
 
     /// 并发线程数
-    /// 默认为4
+    /// 默认为1
     #[arg(short, long, default_value = "1")]
     thread: usize,
-}
 
-#[cfg(feature = "matrix")]
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Home Server URL
-    #[arg(long, env = "HOME_SERVER_URL")]
-    home_server_url: String,
+    /// 可以使用管理命令的用户，逗号分隔
+    /// Matrix 为用户 ID，例如 @admin:example.org；VoceChat 为 UID
+    #[arg(long, env = "ADMINS", value_delimiter = ',')]
+    admins: Vec<String>,
 
-    /// 发送到的房间ID
-    #[arg(long, env = "ROOM_ID")]
-    room_id: String,
+    #[command(flatten)]
+    route: route::RouteArgs,
 
-    /// 机器人用户名
-    #[arg(short, long, env = "USER")]
-    user: String,
+    #[command(flatten)]
+    caption: caption::CaptionArgs,
 
-    /// 机器人密码
-    #[arg(short, long, env = "PASSWORD")]
-    password: String,
+    #[command(flatten)]
+    archive: sink::archive::ArchiveArgs,
 
-    /// 服务存储文件的目录
-    /// 默认为data
-    #[arg(short, long, default_value = "data", env = "DATA_DIR")]
-    data_dir: String,
+    #[command(flatten)]
+    s3: sink::s3::S3Args,
 
-    /// 并发线程数
-    /// 默认为1
-    #[arg(short, long, default_value = "1")]
-    thread: usize,
+    #[command(flatten)]
+    webhook: sink::webhook::WebhookArgs,
+
+    #[command(flatten)]
+    email: sink::email::EmailArgs,
+
+    #[command(flatten)]
+    web: web::WebArgs,
+
+    #[command(flatten)]
+    admin: admin::AdminArgs,
+
+    #[command(flatten)]
+    config: config::ConfigArgs,
+
+    #[command(flatten)]
+    control: control::ControlArgs,
+
+    #[command(flatten)]
+    source: yande::SourceArgs,
+
+    #[command(flatten)]
+    resize: resize::ResizeArgs,
+
+    #[command(flatten)]
+    retention: db::RetentionArgs,
+
+    #[cfg(feature = "matrix")]
+    #[command(flatten)]
+    matrix: bot::matrix::MatrixArgs,
+
+    #[cfg(feature = "voce")]
+    #[command(flatten)]
+    voce: bot::voce::VoceArgs,
+
+    #[command(subcommand)]
+    command: Option<cli::Command>,
+}
+
+impl Args {
+    /// 检查所选后端需要的参数和模板
+    fn validate(self) -> Result<Self, clap::Error> {
+        match self.backend {
+            #[cfg(feature = "matrix")]
+            bot::Backend::Matrix => self.matrix.validate()?,
+            #[cfg(feature = "voce")]
+            bot::Backend::Voce => self.voce.validate()?,
+        }
+        self.webhook.validate()?;
+        self.admin.validate()?;
+        self.web.validate()?;
+        Ok(self)
+    }
+
+    /// 只在启动时读取的参数在重新加载时保留当前的值，返回被忽略的修改
+    fn keep_startup_only(&mut self, current: &Args) -> Vec<&'static str> {
+        let (web, admin) = (&current.web, &current.admin);
+        let changed = [
+            ("BACKEND", keep(&mut self.backend, &current.backend)),
+            ("DATA_DIR", keep(&mut self.data_dir, &current.data_dir)),
+            ("HTTP_ADDR", keep(&mut self.web.http_addr, &web.http_addr)),
+            (
+                "ADMIN_ADDR",
+                keep(&mut self.admin.admin_addr, &admin.admin_addr),
+            ),
+            #[cfg(feature = "matrix")]
+            ("Matrix login", self.matrix.keep_login(&current.matrix)),
+            #[cfg(feature = "voce")]
+            (
+                "API_KEY",
+                keep(&mut self.voce.api_key, &current.voce.api_key),
+            ),
+            #[cfg(feature = "voce")]
+            (
+                "SERVER_DOMAIN",
+                keep(&mut self.voce.server_domain, &current.voce.server_domain),
+            ),
+        ];
+        changed
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect()
+    }
+}
+
+/// 值不同时换回 `current`，返回是否有修改
+fn keep<T: PartialEq + Clone>(value: &mut T, current: &T) -> bool {
+    let changed = value != current;
+    if changed {
+        *value = current.clone();
+    }
+    changed
 }
 
 static ARGS: OnceLock<Args> = OnceLock::new();
+/// 重新加载后的配置，旧的配置可能还被借用，不释放
+static RELOADED: RwLock<Option<&'static Args>> = RwLock::new(None);
 static STOP_SIGNAL: AtomicBool = AtomicBool::new(false);
 
 #[tokio::main]
 async fn main() {
+    if let Some(cli::Command::Healthcheck) = args().command {
+        std::process::exit(health::check().await);
+    }
+
+    health::init();
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
     log::info!("start yande.rs bot");
-    log::info!("args: {:?}", args());
 
     create_dir_all(&args().data_dir).unwrap();
+    let tmp_dir = std::path::Path::new(&args().data_dir).join("tmp");
+    create_dir_all(&tmp_dir).unwrap();
 
-    #[cfg(feature = "matrix")]
-    {
-        log::info!("test login");
-        bot::matrix::ROOM.get_or_init(bot::matrix::room_init);
-        tokio::spawn(bot::matrix::e2ee::sync(
-            bot::matrix::CLIENT.get().unwrap().clone(),
-        ));
-        log::info!("login success");
+    let result = match &args().command {
+        Some(cli::Command::Scan {
+            dry_run: true,
+            preview_dir,
+            ..
+        }) => preview::run(preview_dir.as_deref()).await,
+        None | Some(cli::Command::Run) | Some(cli::Command::Scan { once: false, .. }) => {
+            serve().await
+        }
+        Some(cli::Command::Scan { once: true, .. }) => scan_once().await,
+        Some(cli::Command::Post { id }) => post_once(*id).await,
+        Some(cli::Command::Info { id }) => cli::info(*id).await,
+        Some(cli::Command::Db { command }) => open_db().and_then(|()| cli::db(command)),
+        Some(cli::Command::Verify) => bot::verify().await,
+        Some(cli::Command::Healthcheck) => unreachable!("handled before init"),
+    };
+    if let Err(e) = result {
+        log::error!("{}", e);
+        std::process::exit(1);
     }
+}
 
-    let tmp_dir = std::path::Path::new(&args().data_dir).join("tmp");
-    create_dir_all(&tmp_dir).unwrap();
+/// 提前打开数据库，打开失败时返回错误，而不是在第一次使用时 panic
+fn open_db() -> Result<()> {
+    let db = db::DB::open()?;
+    DB_HANDLE.get_or_init(|| db);
+    Ok(())
+}
+
+// 一次性运行的子命令不重试，数据库或 Matrix 存储被运行中的服务锁定时直接退出
+async fn scan_once() -> Result<()> {
+    open_db()?;
+    bot::init_once().await?;
+    let result = scan().await;
+    // 一次性运行时没有后台任务，扫描后顺便检查摘要
+    sink::email::send_if_due()
+        .await
+        .unwrap_or_else(|e| log::error!("send email digest failed: {}", e));
+    result
+}
+
+async fn post_once(id: i64) -> Result<()> {
+    open_db()?;
+    bot::init_once().await?;
+    post(id).await
+}
+
+/// 持续运行，按间隔扫描，同时提供管理接口和 HTTP 服务
+async fn serve() -> Result<()> {
+    open_db()?;
+    config::watch();
+    tokio::spawn(admin::serve());
+
+    bot::init().await?;
+
+    tokio::spawn(web::serve());
+    tokio::spawn(sink::email::schedule());
 
     let ctrlc = tokio::signal::ctrl_c();
-    let interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
     tokio::pin!(ctrlc);
-    tokio::pin!(interval);
+    // 每次扫描后按当前配置的间隔计算下次扫描的时间
+    let mut next = tokio::time::Instant::now();
     while !STOP_SIGNAL.load(Ordering::Relaxed) {
         tokio::select! {
             _ = &mut ctrlc => {
                 log::info!("Ctrl-C received, exiting...");
                 STOP_SIGNAL.store(true, Ordering::Relaxed);
             }
-            _ = interval.tick() => {
-                log::info!("start scan");
-                run().await.unwrap_or_else(|e| log::error!("run failed: {}", e));
-                DB_HANDLE.get_or_init(db::DB::init).auto_remove().unwrap();
-                log::info!("scan finished, sleep 1 hour");
+            _ = control::scan_requested() => {
+                scan().await.unwrap_or_else(|e| log::error!("run failed: {}", e));
+            }
+            _ = tokio::time::sleep_until(next) => {
+                next = tokio::time::Instant::now() + control::scan_interval();
+                if control::is_paused() {
+                    log::info!("paused, skip scan");
+                    continue;
+                }
+                scan().await.unwrap_or_else(|e| log::error!("run failed: {}", e));
             }
         }
     }
+    Ok(())
 }
 
-pub(crate) fn args() -> &'static Args {
-    ARGS.get_or_init(Args::parse)
+async fn scan() -> Result<()> {
+    log::info!("start scan");
+    control::scan_started();
+    let metrics = metrics::metrics();
+    let timer = metrics.scan_duration.start_timer();
+    let result = run().await;
+    match result {
+        Ok(()) => {
+            metrics.scans.with_label_values(&["ok"]).inc();
+            metrics.last_success.set(control::now() as i64);
+        }
+        Err(_) => metrics.scans.with_label_values(&["error"]).inc(),
+    }
+    timer.observe_duration();
+    DB_HANDLE.get_or_init(db::DB::init).auto_remove().unwrap();
+    control::scan_finished();
+    log::info!("scan finished");
+    result
 }
 
-async fn run() -> Result<()> {
-    let resp = yande::get("https://yande.re/post/popular_recent").await?;
-
-    let mut image_list = yande::get_image_list(&resp)?;
+pub(crate) fn args() -> &'static Args {
+    if let Some(args) = *RELOADED.read().unwrap() {
+        return args;
+    }
+    ARGS.get_or_init(|| {
+        config::parse()
+            .and_then(Args::validate)
+            .unwrap_or_else(|e| e.exit())
+    })
+}
 
-    let resp = yande::get("https://yande.re/post/popular_recent?period=1w")
-        .await
-        .unwrap_or_default();
+pub(crate) fn set_args(args: Args) {
+    *RELOADED.write().unwrap() = Some(Box::leak(Box::new(args)));
+}
 
-    image_list.extend(yande::get_image_list(&resp).unwrap_or_default());
+async fn run() -> Result<()> {
+    let image_list = yande::fetch_sources().await?;
     let download_list = yande::get_download_list(image_list).await?;
 
     let semaphore = Arc::new(Semaphore::new(args().thread));
@@ -144,36 +311,10 @@ async fn run() -> Result<()> {
             break;
         }
         let semaphore_clone = Arc::clone(&semaphore);
+        control::enqueue(id);
         tasks.push(tokio::spawn(async move {
             let _permit = semaphore_clone.acquire().await.unwrap();
-            let msg =
-                format!("来源：[https://yande.re/post/show/{id}](https://yande.re/post/show/{id})");
-            bot::send_msg(&msg)
-                .await
-                .unwrap_or_else(|e| log::error!("send msg failed: {}", e));
-            for (id, url) in img_data.url.iter() {
-                log::info!("prepare download: {}", id);
-                let path = match yande::download_img((*id, url)).await {
-                    Ok(path) => path,
-                    Err(e) => {
-                        log::error!("download failed: {}", e);
-                        continue;
-                    }
-                };
-
-                let path = match resize::resize_and_compress(&path) {
-                    Ok(path) => path,
-                    Err(e) => {
-                        log::error!("resize {id} failed: {}", e);
-                        continue;
-                    }
-                };
-                log::info!("upload: {}", id);
-
-                bot::send_attachment(&path)
-                    .await
-                    .unwrap_or_else(|e| log::error!("send attachment failed: {}", e));
-            }
+            deliver(id, img_data).await;
         }));
     }
 
@@ -182,3 +323,117 @@ async fn run() -> Result<()> {
     }
     Ok(())
 }
+
+/// 强制推送帖子所在的家族，不检查是否推送过，发送成功后记为已扫描
+pub(crate) async fn post(id: i64) -> Result<()> {
+    let (id, img_data) = yande::get_image_info(id).await?;
+    let ids: Vec<_> = img_data.url.iter().map(|(id, _)| id.to_string()).collect();
+    if !deliver(id, img_data).await {
+        anyhow::bail!("{} was not sent to any target", id);
+    }
+    let db = DB_HANDLE.get_or_init(db::DB::init);
+    for id in ids {
+        db.insert(&id)?;
+    }
+    Ok(())
+}
+
+/// 下载、处理并发送一个帖子家族，返回是否至少发送到了一个目标
+pub(crate) async fn deliver(id: i64, img_data: yande::ImgData) -> bool {
+    let mut files = Vec::new();
+    for (img_id, url) in img_data.url.iter() {
+        log::info!("prepare download: {}", img_id);
+        let original = match yande::download_img((*img_id, url)).await {
+            Ok(path) => path,
+            Err(e) => {
+                log::error!("download failed: {}", e);
+                continue;
+            }
+        };
+
+        let timer = metrics::metrics().resize_duration.start_timer();
+        let path = resize::resize_and_compress(&original);
+        timer.observe_duration();
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => {
+                log::error!("resize {img_id} failed: {}", e);
+                continue;
+            }
+        };
+
+        sink::store(&sink::Image {
+            id: *img_id,
+            parent: id,
+            post: img_data.posts.get(img_id),
+            original: &original,
+            processed: &path,
+        })
+        .await;
+        if original != path {
+            std::fs::remove_file(&original)
+                .unwrap_or_else(|e| log::warn!("remove {:?} failed: {}", original, e));
+        }
+        files.push((*img_id, path));
+    }
+
+    // 整个家族处理完后一次性发送，保证说明和图片的顺序
+    log::info!("upload: {}", id);
+    let paths: Vec<_> = files.iter().map(|(_, path)| path.clone()).collect();
+    let mut messages = Vec::new();
+    let mut delivered = false;
+    match caption::render(id, &img_data) {
+        Ok(caption) => {
+            let rating = img_data
+                .posts
+                .get(&id)
+                .map(|post| post.rating.as_str())
+                .unwrap_or_default();
+            let metrics = metrics::metrics();
+            for target in route::targets(id, &img_data) {
+                let timer = metrics
+                    .upload_duration
+                    .with_label_values(&["bot"])
+                    .start_timer();
+                let (sent, result) = bot::send_post(target, rating, &caption, &paths).await;
+                timer.observe_duration();
+                match result {
+                    Ok(()) => {
+                        metrics.posts_sent.with_label_values(&["ok"]).inc();
+                        delivered = true;
+                    }
+                    Err(e) => {
+                        metrics.posts_sent.with_label_values(&["error"]).inc();
+                        log::error!(
+                            "send post {} to {} failed after {} messages: {}",
+                            id,
+                            target,
+                            sent.len(),
+                            e
+                        );
+                        // 已经发出的消息仍然可以收到回应
+                        delivered |= !sent.is_empty();
+                    }
+                }
+                messages.extend(sent);
+            }
+        }
+        Err(e) => log::error!("render caption of {} failed: {}", id, e),
+    }
+    sink::notify(id, &img_data, &files).await;
+    // 全部发送失败的帖子不计入反馈，也不出现在历史记录和订阅源中
+    if delivered {
+        feedback::record_delivery(id, &img_data, &messages)
+            .unwrap_or_else(|e| log::error!("record feedback of {} failed: {}", id, e));
+        DB_HANDLE
+            .get_or_init(db::DB::init)
+            .record(&db::History::new(id, &img_data))
+            .unwrap_or_else(|e| log::error!("record history failed: {}", e));
+    }
+    for (_, file) in files {
+        std::fs::remove_file(&file)
+            .unwrap_or_else(|e| log::warn!("remove {:?} failed: {}", file, e));
+    }
+    control::dequeue(id);
+    delivered
+}
@@ -0,0 +1,167 @@
+use std::sync::OnceLock;
+
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::{control, db::DB, yande::DB_HANDLE};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub struct Metrics {
+    registry: Registry,
+    /// 扫描次数，按结果 ok/error
+    pub scans: IntCounterVec,
+    pub scan_duration: Histogram,
+    /// 从热门列表取到的帖子
+    pub posts_fetched: IntCounter,
+    /// 被过滤的帖子，按原因 exists/info_error/score/blacklist
+    pub posts_filtered: IntCounterVec,
+    /// 发送到房间或频道的帖子，按结果 ok/error
+    pub posts_sent: IntCounterVec,
+    /// 对 yande.re 的请求，按状态码，没有响应时为 error
+    pub http_requests: IntCounterVec,
+    pub download_bytes: IntCounter,
+    pub resize_duration: Histogram,
+    /// 上传或发送耗时，按 bot/archive/s3/webhook/email，未启用的 sink 不记录
+    pub upload_duration: HistogramVec,
+    pub last_success: IntGauge,
+    db_size: IntGauge,
+    queue_depth: IntGauge,
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("register metric");
+    metric
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("yande".to_string()), None).unwrap();
+        let counter =
+            |name: &str, help: &str| register(&registry, IntCounter::new(name, help).unwrap());
+        let counter_vec = |name: &str, help: &str, label: &str| {
+            register(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), &[label]).unwrap(),
+            )
+        };
+        let gauge =
+            |name: &str, help: &str| register(&registry, IntGauge::new(name, help).unwrap());
+        let histogram = |name: &str, help: &str, buckets: Vec<f64>| {
+            register(
+                &registry,
+                Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets)).unwrap(),
+            )
+        };
+
+        Metrics {
+            scans: counter_vec("scans_total", "Scans by result", "result"),
+            scan_duration: histogram(
+                "scan_duration_seconds",
+                "Duration of a scan",
+                prometheus::exponential_buckets(1.0, 2.0, 12).unwrap(),
+            ),
+            posts_fetched: counter("posts_fetched_total", "Posts fetched from popular lists"),
+            posts_filtered: counter_vec(
+                "posts_filtered_total",
+                "Posts filtered by reason",
+                "reason",
+            ),
+            posts_sent: counter_vec(
+                "posts_sent_total",
+                "Posts sent to rooms by result",
+                "result",
+            ),
+            http_requests: counter_vec(
+                "http_requests_total",
+                "Requests to yande.re by status",
+                "status",
+            ),
+            download_bytes: counter("download_bytes_total", "Bytes of downloaded images"),
+            resize_duration: histogram(
+                "resize_duration_seconds",
+                "Duration of resizing and compressing an image",
+                prometheus::exponential_buckets(0.05, 2.0, 10).unwrap(),
+            ),
+            upload_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("upload_duration_seconds", "Duration of uploads by sink")
+                        .buckets(prometheus::exponential_buckets(0.1, 2.0, 10).unwrap()),
+                    &["sink"],
+                )
+                .unwrap(),
+            ),
+            last_success: gauge(
+                "last_successful_scan_timestamp_seconds",
+                "Unix time of the last successful scan",
+            ),
+            db_size: gauge("db_size_bytes", "Size of the database on disk"),
+            queue_depth: gauge("queue_depth", "Posts waiting to be sent"),
+            registry,
+        }
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(render))
+}
+
+/// 数据库大小和队列长度在抓取时读取
+async fn render() -> impl IntoResponse {
+    let metrics = metrics();
+    match DB_HANDLE.get_or_init(DB::init).size_on_disk() {
+        Ok(size) => metrics.db_size.set(size as i64),
+        Err(e) => log::error!("read db size failed: {}", e),
+    }
+    metrics.queue_depth.set(control::queue().len() as i64);
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut buffer) {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+        metrics.posts_filtered.with_label_values(&["score"]).inc();
+        metrics
+            .upload_duration
+            .with_label_values(&["s3"])
+            .observe(0.5);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&metrics.registry.gather(), &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains(r#"yande_posts_filtered_total{reason="score"} 1"#));
+        assert!(text.contains(r#"yande_upload_duration_seconds_count{sink="s3"} 1"#));
+        assert!(text.contains("yande_last_successful_scan_timestamp_seconds 0"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::Utc;
+
+use crate::{
+    args, caption, control,
+    db::DB,
+    resize, route,
+    yande::{self, ImgData, Skipped, DB_HANDLE},
+};
+
+/// 预览报告，写到预览目录的 report.json
+#[derive(Debug, serde::Serialize)]
+struct Report {
+    created_at: u64,
+    threshold: u64,
+    posts: Vec<Preview>,
+    skipped: Vec<Skipped>,
+}
+
+/// 一个会被推送的家族，`files` 为处理后的图片
+#[derive(Debug, serde::Serialize)]
+struct Preview {
+    id: i64,
+    score: u64,
+    rating: String,
+    targets: Vec<&'static str>,
+    caption: String,
+    files: Vec<PathBuf>,
+    errors: Vec<String>,
+}
+
+/// 完整运行一次扫描，但不推送、不调用 sink，也不把帖子记为已扫描
+/// 处理后的图片和报告写到 `dir`，默认为数据目录下的 preview/<时间>
+/// 使用数据库的副本，服务运行时也可以预览
+pub async fn run(dir: Option<&Path>) -> Result<()> {
+    let snapshot = Path::new(&args().data_dir)
+        .join("tmp")
+        .join(format!("preview-db-{}", std::process::id()));
+    copy_db(&Path::new(&args().data_dir).join("db"), &snapshot)?;
+    let db = DB::open_path(&snapshot)?;
+    DB_HANDLE.get_or_init(|| db);
+
+    let result = preview(dir).await;
+    std::fs::remove_dir_all(&snapshot)
+        .unwrap_or_else(|e| log::warn!("remove {:?} failed: {}", snapshot, e));
+    result
+}
+
+/// 复制数据库目录，跳过其中 matrix-sdk 的存储
+/// 运行中的服务可能正在写入，副本可能缺少最近的修改，对预览没有影响
+fn copy_db(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    if !from.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with("matrix-sdk-") {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            copy_db(&entry.path(), &to.join(&name))?;
+        } else {
+            std::fs::copy(entry.path(), to.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+async fn preview(dir: Option<&Path>) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => Path::new(&args().data_dir)
+            .join("preview")
+            .join(Utc::now().format("%Y%m%d-%H%M%S").to_string()),
+    };
+    std::fs::create_dir_all(&dir)?;
+
+    let image_list = yande::fetch_sources().await?;
+    let (download_list, skipped) = yande::select_posts(image_list, true).await?;
+    let mut posts = Vec::new();
+    for (id, img_data) in download_list {
+        log::info!("preview: {}", id);
+        posts.push(prepare(&dir, id, &img_data).await);
+    }
+
+    let report = Report {
+        created_at: control::now(),
+        threshold: control::threshold(),
+        posts,
+        skipped,
+    };
+    std::fs::write(dir.join("report.json"), serde_json::to_vec_pretty(&report)?)?;
+    print(&report);
+    println!("preview written to {}", dir.display());
+    Ok(())
+}
+
+/// 和推送时一样下载、压缩并生成说明，图片移动到预览目录
+async fn prepare(dir: &Path, id: i64, img_data: &ImgData) -> Preview {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    for (img_id, url) in img_data.url.iter() {
+        let original = match yande::download_img((*img_id, url)).await {
+            Ok(path) => path,
+            Err(e) => {
+                errors.push(format!("download {img_id} failed: {e}"));
+                continue;
+            }
+        };
+        match resize::resize_and_compress(&original) {
+            Ok(path) => {
+                let ext = path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .unwrap_or("jpg");
+                let dest = dir.join(format!("{id}_{img_id}.{ext}"));
+                // 预览目录可能在其他文件系统上，不能直接 rename
+                match std::fs::copy(&path, &dest) {
+                    Ok(_) => files.push(dest),
+                    Err(e) => errors.push(format!("copy {img_id} failed: {e}")),
+                }
+                if path != original {
+                    std::fs::remove_file(&path)
+                        .unwrap_or_else(|e| log::warn!("remove {:?} failed: {}", path, e));
+                }
+            }
+            Err(e) => errors.push(format!("resize {img_id} failed: {e}")),
+        }
+        std::fs::remove_file(&original)
+            .unwrap_or_else(|e| log::warn!("remove {:?} failed: {}", original, e));
+    }
+
+    let caption = match caption::render(id, img_data) {
+        Ok(caption) => caption.text,
+        Err(e) => {
+            errors.push(format!("render caption failed: {e}"));
+            String::new()
+        }
+    };
+    Preview {
+        id,
+        score: img_data.score,
+        rating: img_data
+            .posts
+            .get(&id)
+            .map(|post| post.rating.clone())
+            .unwrap_or_default(),
+        targets: route::targets(id, img_data),
+        caption,
+        files,
+        errors,
+    }
+}
+
+fn print(report: &Report) {
+    println!(
+        "would post {}, skipped {} (threshold {})",
+        report.posts.len(),
+        report.skipped.len(),
+        report.threshold
+    );
+    for post in report.posts.iter() {
+        println!(
+            "  {} score {} [{}] -> {} ({} files)",
+            post.id,
+            post.score,
+            post.rating,
+            post.targets.join(", "),
+            post.files.len()
+        );
+        for error in post.errors.iter() {
+            println!("    {}", error);
+        }
+    }
+    for skipped in report.skipped.iter() {
+        println!(
+            "  {} skipped, {}: {}",
+            skipped.id, skipped.reason, skipped.detail
+        );
+    }
+}
@@ -6,16 +6,33 @@ use image_compressor::{
     Factor,
 };
 
-const LIMIT: usize = 1920;
+use crate::args;
+
+#[derive(clap::Args, Debug)]
+pub struct ResizeArgs {
+    /// 压缩后图片长边的最大像素数
+    #[arg(long, env = "RESIZE_LIMIT", default_value = "1920")]
+    pub resize_limit: usize,
+
+    /// JPEG 压缩质量，1-100
+    #[arg(long, env = "JPEG_QUALITY", default_value = "85", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub jpeg_quality: u8,
+}
 
 pub fn resize_and_compress(path: &Path) -> Result<PathBuf> {
+    let config = &args().resize;
+    compress(path, config.resize_limit, config.jpeg_quality)
+}
+
+fn compress(path: &Path, limit: usize, quality: u8) -> Result<PathBuf> {
     let source = path;
-    let dest = path.parent().unwrap_or(Path::new("."));
-    std::fs::create_dir_all(dest)?;
+    // 输出到单独的目录，避免 jpg 原图被覆盖
+    let dest = path.parent().unwrap_or(Path::new(".")).join("processed");
+    std::fs::create_dir_all(&dest)?;
     let mut comp = Compressor::new(source, &dest);
     comp.set_factor(Factor::new_with_resize_type(
-        85.0,
-        ResizeType::LongestSidePixels(LIMIT),
+        quality as f32,
+        ResizeType::LongestSidePixels(limit),
     ));
     comp.set_delete_source(false);
     comp.set_overwrite_dest(true);
@@ -36,7 +53,7 @@ mod test {
         env::set_var("RUST_LOG", "debug");
         env_logger::init();
         let path = Path::new(r"D:\Project\yande_popular\data\tmp\Term.png");
-        let path = resize_and_compress(path)?;
+        let path = compress(path, 1920, 85)?;
         log::debug!("path: {:?}", path);
         Ok(())
     }
@@ -0,0 +1,169 @@
+use std::{ops::Range, str::FromStr};
+
+use crate::{args, bot, yande::ImgData};
+
+#[derive(clap::Args, Debug)]
+pub struct RouteArgs {
+    /// 路由规则，格式为 `条件 条件 => 房间或频道`，多条规则用分号分隔
+    /// 条件：rating:q,e  tag:foo  -tag:bar  score:100..200  source:pixiv  *
+    /// 所有条件都满足的规则都会发送，没有规则匹配时发到默认的房间或频道
+    #[arg(long = "route", env = "ROUTES", value_delimiter = ';')]
+    pub routes: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    conditions: Vec<Condition>,
+    target: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Any,
+    Rating(Vec<String>),
+    Tag(String),
+    NotTag(String),
+    Score(Range<u64>),
+    Source(String),
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (conditions, target) = s
+            .split_once("=>")
+            .ok_or(anyhow::anyhow!("missing `=>` in route: {s}"))?;
+        let target = target.trim();
+        if target.is_empty() {
+            anyhow::bail!("missing target in route: {s}");
+        }
+        let conditions = conditions
+            .split_whitespace()
+            .map(Condition::from_str)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Rule {
+            conditions,
+            target: target.to_string(),
+        })
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Condition::Any);
+        }
+        let (key, value) = s
+            .split_once(':')
+            .ok_or(anyhow::anyhow!("invalid route condition: {s}"))?;
+        let condition = match key {
+            "rating" => Condition::Rating(value.split(',').map(str::to_string).collect()),
+            "tag" => Condition::Tag(value.to_string()),
+            "-tag" => Condition::NotTag(value.to_string()),
+            "source" => Condition::Source(value.to_string()),
+            "score" => {
+                let (min, max) = value.split_once("..").unwrap_or((value, ""));
+                let min = if min.is_empty() { 0 } else { min.parse()? };
+                let max = if max.is_empty() {
+                    u64::MAX
+                } else {
+                    max.parse()?
+                };
+                Condition::Score(min..max)
+            }
+            _ => anyhow::bail!("unknown route condition: {s}"),
+        };
+        Ok(condition)
+    }
+}
+
+impl Rule {
+    fn matches(&self, id: i64, img_data: &ImgData) -> bool {
+        let post = img_data.posts.get(&id);
+        let has_tag = |tag: &str| {
+            img_data
+                .posts
+                .values()
+                .any(|post| post.tag_list().contains(&tag))
+        };
+
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Any => true,
+            Condition::Rating(ratings) => post.is_some_and(|post| ratings.contains(&post.rating)),
+            Condition::Tag(tag) => has_tag(tag),
+            Condition::NotTag(tag) => !has_tag(tag),
+            Condition::Score(range) => range.contains(&img_data.score),
+            Condition::Source(source) => post.is_some_and(|post| post.source.contains(source)),
+        })
+    }
+}
+
+/// 路由规则中出现的所有房间或频道
+#[cfg_attr(not(feature = "matrix"), allow(dead_code))]
+pub fn all_targets() -> Vec<&'static str> {
+    let mut targets: Vec<&str> = Vec::new();
+    for rule in args().route.routes.iter() {
+        if !targets.contains(&rule.target.as_str()) {
+            targets.push(&rule.target);
+        }
+    }
+    targets
+}
+
+/// 帖子要发送到的房间或频道，按规则顺序去重
+pub fn targets(id: i64, img_data: &ImgData) -> Vec<&'static str> {
+    let mut targets = Vec::new();
+    for rule in args().route.routes.iter() {
+        if rule.matches(id, img_data) && !targets.contains(&rule.target.as_str()) {
+            targets.push(rule.target.as_str());
+        }
+    }
+    if targets.is_empty() {
+        targets.push(bot::default_target());
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+
+    use super::*;
+    use crate::yande::Post;
+
+    #[test]
+    fn test_rule() {
+        let rule: Rule = "rating:q,e -tag:foo score:100.. => !room:example.org"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.target, "!room:example.org");
+        assert_eq!(rule.conditions.len(), 3);
+        assert!("rating:q".parse::<Rule>().is_err());
+        assert!("color:red => 1".parse::<Rule>().is_err());
+
+        let img_data = |rating: &str, tags: &str, score| ImgData {
+            score,
+            url: VecDeque::from([(1, String::new())]),
+            posts: HashMap::from([(
+                1,
+                Post {
+                    id: 1,
+                    rating: rating.to_string(),
+                    tags: tags.to_string(),
+                    ..Default::default()
+                },
+            )]),
+        };
+        assert!(rule.matches(1, &img_data("q", "bar", 100)));
+        assert!(!rule.matches(1, &img_data("s", "bar", 100)));
+        assert!(!rule.matches(1, &img_data("q", "foo bar", 100)));
+        assert!(!rule.matches(1, &img_data("e", "bar", 99)));
+
+        let any: Rule = "* => 2".parse().unwrap();
+        assert!(any.matches(1, &img_data("s", "", 0)));
+    }
+}
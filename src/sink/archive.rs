@@ -0,0 +1,75 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+use super::{render, vars, xmp, Image};
+use crate::{args, metrics::metrics, yande::Post};
+
+#[derive(clap::Args, Debug)]
+pub struct ArchiveArgs {
+    /// 本地归档目录
+    /// 不设置则不归档
+    #[arg(long, env = "ARCHIVE_DIR")]
+    pub archive_dir: Option<String>,
+
+    /// 归档路径模板
+    /// 可用变量：source yyyy mm dd id parent artist rating score md5 ext
+    #[arg(
+        long,
+        env = "ARCHIVE_TEMPLATE",
+        default_value = "{source}/{yyyy}/{mm}/{id}_{artist}.{ext}"
+    )]
+    pub archive_template: String,
+
+    /// 同时归档压缩后的图片，保存在归档目录的 processed 下
+    #[arg(long, env = "ARCHIVE_PROCESSED")]
+    pub archive_processed: bool,
+}
+
+pub fn store(image: &Image<'_>) -> Result<()> {
+    let config = &args().archive;
+    let Some(dir) = &config.archive_dir else {
+        return Ok(());
+    };
+    let _timer = metrics()
+        .upload_duration
+        .with_label_values(&["archive"])
+        .start_timer();
+    let dir = Path::new(dir);
+
+    let dest = dir.join(render(
+        &config.archive_template,
+        &vars(image, image.original),
+    ));
+    save(image.original, &dest, image.post)?;
+    log::info!("archived {} to {}", image.id, dest.display());
+
+    if config.archive_processed && image.processed != image.original {
+        let dest = dir.join("processed").join(render(
+            &config.archive_template,
+            &vars(image, image.processed),
+        ));
+        save(image.processed, &dest, image.post)?;
+    }
+    Ok(())
+}
+
+fn save(src: &Path, dest: &Path, post: Option<&Post>) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut data = fs::read(src)?;
+    if let Some(post) = post {
+        match xmp::embed(&data, &xmp::packet(post)) {
+            Ok(embedded) => data = embedded,
+            Err(e) => log::warn!("embed xmp into {} failed: {}", dest.display(), e),
+        }
+        // 在完整文件名后追加，文件名中有点号时不会截断
+        let mut sidecar = dest.as_os_str().to_owned();
+        sidecar.push(".json");
+        fs::write(sidecar, serde_json::to_vec_pretty(post)?)?;
+    }
+    fs::write(dest, data)?;
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use anyhow::Result;
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::{
+    args, control,
+    db::{History, DB},
+    metrics::metrics,
+    yande::{self, DB_HANDLE},
+};
+
+const LAST_DIGEST: &str = "email_last_digest";
+/// 检查是否需要发送摘要的间隔，和扫描无关，暂停时也会发送
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// 明文，适合本地测试用的 SMTP 服务
+    None,
+    Starttls,
+    /// 隐式 TLS（SMTPS）
+    Tls,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestPeriod {
+    Daily,
+    Weekly,
+}
+
+impl DigestPeriod {
+    pub fn seconds(self) -> u64 {
+        match self {
+            DigestPeriod::Daily => 60 * 60 * 24,
+            DigestPeriod::Weekly => 60 * 60 * 24 * 7,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct EmailArgs {
+    /// SMTP 服务器
+    /// 不设置则不发送邮件摘要
+    #[arg(long, env = "SMTP_HOST")]
+    pub smtp_host: Option<String>,
+
+    /// 默认按加密方式取 25、587 或 465
+    #[arg(long, env = "SMTP_PORT")]
+    pub smtp_port: Option<u16>,
+
+    #[arg(long, env = "SMTP_TLS", value_enum, default_value = "starttls")]
+    pub smtp_tls: SmtpTls,
+
+    #[arg(long, env = "SMTP_USER")]
+    pub smtp_user: Option<String>,
+
+    #[arg(long, env = "SMTP_PASSWORD", hide_env_values = true)]
+    pub smtp_password: Option<String>,
+
+    /// 发件人，例如 "Yande Bot <bot@example.com>"
+    #[arg(
+        long,
+        env = "MAIL_FROM",
+        default_value = "yande_popular <yande_popular@localhost>"
+    )]
+    pub mail_from: String,
+
+    /// 收件人，逗号分隔
+    #[arg(long, env = "MAIL_TO", value_delimiter = ',')]
+    pub mail_to: Vec<String>,
+
+    #[arg(long, env = "DIGEST_PERIOD", value_enum, default_value = "daily")]
+    pub digest_period: DigestPeriod,
+
+    /// 摘要中包含的帖子数量
+    #[arg(long, env = "DIGEST_TOP", default_value = "10")]
+    pub digest_top: usize,
+}
+
+/// 持续运行时定期检查并发送摘要
+pub async fn schedule() {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        send_if_due()
+            .await
+            .unwrap_or_else(|e| log::error!("send email digest failed: {}", e));
+    }
+}
+
+/// 距离上次发送超过一个周期时发送摘要
+pub async fn send_if_due() -> Result<()> {
+    let config = &args().email;
+    if config.smtp_host.is_none() || config.mail_to.is_empty() {
+        return Ok(());
+    }
+
+    let db = DB_HANDLE.get_or_init(DB::init);
+    let now = control::now();
+    let period = config.digest_period.seconds();
+    let Some(last) = db.get_value::<u64>(LAST_DIGEST)? else {
+        // 首次运行从现在开始计时，避免启动时立即发送
+        db.set_value(LAST_DIGEST, &now)?;
+        return Ok(());
+    };
+    if !is_due(last, now, period) {
+        return Ok(());
+    }
+
+    let timer = metrics()
+        .upload_duration
+        .with_label_values(&["email"])
+        .start_timer();
+    send_digest(now.saturating_sub(period)).await?;
+    timer.observe_duration();
+    db.set_value(LAST_DIGEST, &now)?;
+    Ok(())
+}
+
+/// 发送 `since` 之后推送过的评分最高的帖子
+pub async fn send_digest(since: u64) -> Result<()> {
+    let config = &args().email;
+    let Some(host) = &config.smtp_host else {
+        return Err(anyhow::anyhow!("SMTP_HOST is not set"));
+    };
+
+    let mut list = DB_HANDLE.get_or_init(DB::init).recent(since)?;
+    if list.is_empty() {
+        log::info!("no posts since last digest, skip email");
+        return Ok(());
+    }
+    list.sort_by_key(|history| std::cmp::Reverse(history.score));
+    list.truncate(config.digest_top);
+
+    let mut body = MultiPart::related().singlepart(SinglePart::html(html(&list)));
+    for history in &list {
+        let Some(url) = history
+            .root()
+            .map(|post| post.preview_url.as_str())
+            .filter(|url| !url.is_empty())
+        else {
+            continue;
+        };
+        match yande::get_bytes(url).await {
+            Ok(bytes) => {
+                let content_type = ContentType::parse(
+                    mime_guess::from_path(url)
+                        .first_or(mime_guess::mime::IMAGE_JPEG)
+                        .as_ref(),
+                )?;
+                body = body.singlepart(
+                    Attachment::new_inline(format!("thumb{}", history.id))
+                        .body(bytes, content_type),
+                );
+            }
+            Err(e) => log::warn!("download thumbnail of {} failed: {}", history.id, e),
+        }
+    }
+
+    let mut message =
+        Message::builder()
+            .from(config.mail_from.parse()?)
+            .subject(match config.digest_period {
+                DigestPeriod::Daily => "Yande.re 每日热门",
+                DigestPeriod::Weekly => "Yande.re 每周热门",
+            });
+    for to in &config.mail_to {
+        message = message.to(to.trim().parse()?);
+    }
+    let message = message.multipart(body)?;
+
+    let port = config.smtp_port.unwrap_or(match config.smtp_tls {
+        SmtpTls::None => 25,
+        SmtpTls::Starttls => 587,
+        SmtpTls::Tls => 465,
+    });
+    let mut transport = match config.smtp_tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+    }
+    .port(port);
+    if let (Some(user), Some(password)) = (&config.smtp_user, &config.smtp_password) {
+        transport = transport.credentials(Credentials::new(user.clone(), password.clone()));
+    }
+
+    transport.build().send(message).await?;
+    log::info!("email digest with {} posts sent", list.len());
+    Ok(())
+}
+
+fn is_due(last: u64, now: u64, period: u64) -> bool {
+    now.saturating_sub(last) >= period
+}
+
+fn html(list: &[History]) -> String {
+    let mut html = String::from(
+        r#"<html><body><table style="border-collapse:collapse;font-family:sans-serif">"#,
+    );
+    for history in list {
+        let link = format!("https://yande.re/post/show/{}", history.id);
+        let post = history.root();
+        let artist = post.and_then(|post| post.artist()).unwrap_or("unknown");
+        let thumbnail = match post.filter(|post| !post.preview_url.is_empty()) {
+            Some(_) => format!(
+                r#"<a href="{link}"><img src="cid:thumb{}" style="max-width:300px" /></a>"#,
+                history.id
+            ),
+            None => String::new(),
+        };
+        html.push_str(&format!(
+            concat!(
+                r#"<tr><td style="padding:8px">{thumbnail}</td>"#,
+                r#"<td style="padding:8px;vertical-align:top">"#,
+                r#"<p><a href="{link}">#{id}</a> by {artist}</p>"#,
+                r#"<p>Score: {score}</p></td></tr>"#
+            ),
+            thumbnail = thumbnail,
+            link = link,
+            id = history.id,
+            artist = artist
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;"),
+            score = history.score,
+        ));
+    }
+    html.push_str("</table></body></html>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yande::Post;
+
+    #[test]
+    fn test_is_due() {
+        let day = DigestPeriod::Daily.seconds();
+        assert!(!is_due(1000, 1000 + day - 1, day));
+        assert!(is_due(1000, 1000 + day, day));
+        // 时钟回拨时不发送
+        assert!(!is_due(1000, 500, day));
+    }
+
+    #[test]
+    fn test_html() {
+        let post = |id: i64, preview_url: &str| Post {
+            id,
+            tags: "a<b>&c".to_string(),
+            preview_url: preview_url.to_string(),
+            tag_types: [("a<b>&c".to_string(), "artist".to_string())].into(),
+            ..Default::default()
+        };
+        let list = [
+            History {
+                id: 1,
+                score: 120,
+                delivered_at: 0,
+                posts: vec![post(1, "https://example.org/1.jpg")],
+            },
+            History {
+                id: 2,
+                score: 80,
+                delivered_at: 0,
+                posts: vec![post(2, "")],
+            },
+        ];
+        let html = html(&list);
+        assert!(html.contains(r#"<a href="https://yande.re/post/show/1"><img src="cid:thumb1""#));
+        assert!(
+            html.contains(r#"<a href="https://yande.re/post/show/1">#1</a> by a&lt;b&gt;&amp;c"#)
+        );
+        assert!(html.contains("<p>Score: 120</p>"));
+        assert!(!html.contains("cid:thumb2"));
+        assert!(html.contains("<p>Score: 80</p>"));
+        assert!(html.find("#1</a>") < html.find("#2</a>"));
+    }
+}
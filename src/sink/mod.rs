@@ -0,0 +1,113 @@
+pub mod archive;
+pub mod email;
+pub mod s3;
+pub mod webhook;
+mod xmp;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Datelike, TimeZone, Utc};
+
+use crate::yande::{ImgData, Post};
+
+/// 一张已下载的图片，交给各个 sink 保存
+pub struct Image<'a> {
+    pub id: i64,
+    /// 所在家族的根帖子
+    pub parent: i64,
+    pub post: Option<&'a Post>,
+    pub original: &'a Path,
+    pub processed: &'a Path,
+}
+
+/// 各 sink 只在启用时记录耗时
+pub async fn store(image: &Image<'_>) {
+    archive::store(image).unwrap_or_else(|e| log::error!("archive {} failed: {}", image.id, e));
+    s3::store(image)
+        .await
+        .unwrap_or_else(|e| log::error!("upload {} to s3 failed: {}", image.id, e));
+}
+
+/// 整个帖子（含子帖子）处理完成后调用，`files` 为压缩后的图片
+pub async fn notify(id: i64, img_data: &ImgData, files: &[(i64, PathBuf)]) {
+    webhook::notify(id, img_data, files)
+        .await
+        .unwrap_or_else(|e| log::error!("webhook for {} failed: {}", id, e));
+}
+
+/// 模板中可用的变量
+pub fn vars(image: &Image<'_>, path: &Path) -> HashMap<&'static str, String> {
+    let post = image.post.cloned().unwrap_or_default();
+    let created = Utc
+        .timestamp_opt(post.created_at, 0)
+        .single()
+        .filter(|_| post.created_at > 0)
+        .unwrap_or_else(Utc::now);
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("jpg")
+        .to_string();
+
+    HashMap::from([
+        ("source", "yande.re".to_string()),
+        ("yyyy", format!("{:04}", created.year())),
+        ("mm", format!("{:02}", created.month())),
+        ("dd", format!("{:02}", created.day())),
+        ("id", image.id.to_string()),
+        ("parent", image.parent.to_string()),
+        ("artist", sanitize(post.artist().unwrap_or("unknown"))),
+        ("rating", sanitize(&post.rating)),
+        ("score", post.score.to_string()),
+        ("md5", sanitize(&post.md5)),
+        ("ext", ext),
+    ])
+}
+
+/// 替换模板中的 `{name}`，未知的变量原样保留
+pub fn render(template: &str, vars: &HashMap<&str, String>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) if vars.contains_key(&after[..end]) => {
+                result.push_str(&vars[&after[..end]]);
+                rest = &after[end + 1..];
+            }
+            _ => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// 去掉不能出现在文件名中的字符
+pub fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let vars = HashMap::from([("id", "1".to_string()), ("ext", "png".to_string())]);
+        assert_eq!(render("{id}.{ext}", &vars), "1.png");
+        assert_eq!(render("{id}_{unknown}{", &vars), "1_{unknown}{");
+    }
+}
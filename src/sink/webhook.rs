@@ -0,0 +1,327 @@
+use std::{path::PathBuf, sync::OnceLock, time::Duration};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    multipart::{Form, Part},
+    Client,
+};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+
+use crate::{args, metrics::metrics, yande::ImgData};
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookMode {
+    /// 只发送 JSON
+    Json,
+    /// multipart，payload 字段为 JSON，并附带压缩后的图片
+    Multipart,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WebhookArgs {
+    /// 每个帖子推送完成后回调的地址
+    /// 不设置则不回调
+    #[arg(long, env = "WEBHOOK_URL")]
+    pub webhook_url: Option<String>,
+
+    #[arg(long, env = "WEBHOOK_MODE", value_enum, default_value = "json")]
+    pub webhook_mode: WebhookMode,
+
+    /// JSON 格式的负载模板
+    /// 值为 "{name}" 的字符串会替换为对应的 JSON 值，其他字符串中的 {name} 按文本替换
+    /// 可用变量：id score url rating artist tags caption caption_markdown caption_html images posts
+    #[arg(long, env = "WEBHOOK_TEMPLATE")]
+    pub webhook_template: Option<String>,
+
+    /// 设置后在 X-Signature 头中附带 HMAC-SHA256 签名
+    #[arg(long, env = "WEBHOOK_SECRET", hide_env_values = true)]
+    pub webhook_secret: Option<String>,
+
+    /// 失败后的重试次数
+    #[arg(long, env = "WEBHOOK_RETRIES", default_value = "3")]
+    pub webhook_retries: u32,
+
+    /// 单次请求超时（秒）
+    #[arg(long, env = "WEBHOOK_TIMEOUT", default_value = "30")]
+    pub webhook_timeout: u64,
+}
+
+/// 负载模板中可用的变量
+const VARS: [&str; 11] = [
+    "id",
+    "score",
+    "url",
+    "rating",
+    "artist",
+    "tags",
+    "caption",
+    "caption_markdown",
+    "caption_html",
+    "images",
+    "posts",
+];
+
+impl WebhookArgs {
+    /// 启动和重新加载配置时检查负载模板，拼写错误不会等到推送时才发现
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        let Some(template) = &self.webhook_template else {
+            return Ok(());
+        };
+        let invalid = |message: String| {
+            clap::Error::raw(clap::error::ErrorKind::InvalidValue, format!("{message}\n"))
+        };
+        let template: Value = serde_json::from_str(template)
+            .map_err(|e| invalid(format!("WEBHOOK_TEMPLATE is not valid JSON: {e}")))?;
+        let mut names = Vec::new();
+        placeholders(&template, &mut names);
+        match names
+            .into_iter()
+            .find(|name| !VARS.contains(&name.as_str()))
+        {
+            Some(name) => Err(invalid(format!(
+                "unknown variable {{{name}}} in WEBHOOK_TEMPLATE, available: {}",
+                VARS.join(" ")
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// 模板字符串中形如 `{name}` 的变量
+fn placeholders(template: &Value, names: &mut Vec<String>) {
+    match template {
+        Value::String(text) => {
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find('{') {
+                rest = &rest[start + 1..];
+                let Some(end) = rest.find('}') else {
+                    break;
+                };
+                let name = &rest[..end];
+                if !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                {
+                    names.push(name.to_string());
+                    rest = &rest[end + 1..];
+                }
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| placeholders(item, names)),
+        Value::Object(fields) => fields.values().for_each(|value| placeholders(value, names)),
+        _ => {}
+    }
+}
+
+pub async fn notify(id: i64, img_data: &ImgData, files: &[(i64, PathBuf)]) -> Result<()> {
+    let config = &args().webhook;
+    let Some(url) = &config.webhook_url else {
+        return Ok(());
+    };
+    let _timer = metrics()
+        .upload_duration
+        .with_label_values(&["webhook"])
+        .start_timer();
+
+    let vars = vars(id, img_data);
+    let payload = match &config.webhook_template {
+        Some(template) => expand(&serde_json::from_str(template)?, &vars),
+        None => Value::Object(vars),
+    };
+    let payload = serde_json::to_string(&payload)?;
+    let attachments = match config.webhook_mode {
+        WebhookMode::Json => Vec::new(),
+        WebhookMode::Multipart => files
+            .iter()
+            .map(|(_, path)| Ok((path, std::fs::read(path)?)))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    // multipart 时签名覆盖负载和按顺序拼接的所有图片
+    let signature = config.webhook_secret.as_ref().map(|secret| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(payload.as_bytes());
+        for (_, data) in attachments.iter() {
+            mac.update(data);
+        }
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    });
+
+    let client = CLIENT.get_or_init(Client::new);
+    let mut attempt = 0;
+    loop {
+        let mut request = client
+            .post(url)
+            .timeout(Duration::from_secs(config.webhook_timeout));
+        if let Some(signature) = &signature {
+            request = request.header("X-Signature", signature);
+        }
+        request = match config.webhook_mode {
+            WebhookMode::Json => request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(payload.clone()),
+            WebhookMode::Multipart => request.multipart(form(&payload, &attachments)?),
+        };
+
+        match request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+        {
+            Ok(_) => {
+                log::info!("webhook for {} delivered", id);
+                return Ok(());
+            }
+            Err(e) if attempt < config.webhook_retries => {
+                attempt += 1;
+                log::warn!("webhook for {} failed: {}, retry {}", id, e, attempt);
+                tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn form(payload: &str, attachments: &[(&PathBuf, Vec<u8>)]) -> Result<Form> {
+    let mut form = Form::new().text("payload", payload.to_string());
+    for (index, (path, data)) in attachments.iter().enumerate() {
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("image.jpg")
+            .to_string();
+        let part = Part::bytes(data.clone())
+            .file_name(filename)
+            .mime_str(mime_guess::from_path(path).first_or_octet_stream().as_ref())?;
+        form = form.part(format!("file{index}"), part);
+    }
+    Ok(form)
+}
+
+fn vars(id: i64, img_data: &ImgData) -> Map<String, Value> {
+    let root = img_data.posts.get(&id);
+    let images: Vec<Value> = img_data
+        .url
+        .iter()
+        .map(|(img_id, url)| {
+            let post = img_data.posts.get(img_id);
+            json!({
+                "id": img_id,
+                "url": url,
+                "page": format!("https://yande.re/post/show/{img_id}"),
+                "width": post.map(|post| post.width),
+                "height": post.map(|post| post.height),
+                "md5": post.map(|post| post.md5.as_str()),
+            })
+        })
+        .collect();
+    let posts: Vec<&crate::yande::Post> = img_data
+        .url
+        .iter()
+        .filter_map(|(img_id, _)| img_data.posts.get(img_id))
+        .collect();
+
+    let mut vars = Map::new();
+    vars.insert("id".to_string(), json!(id));
+    vars.insert("score".to_string(), json!(img_data.score));
+    vars.insert(
+        "url".to_string(),
+        json!(format!("https://yande.re/post/show/{id}")),
+    );
+    vars.insert("rating".to_string(), json!(root.map(|post| &post.rating)));
+    vars.insert(
+        "artist".to_string(),
+        json!(root.and_then(|post| post.artist())),
+    );
+    vars.insert(
+        "tags".to_string(),
+        json!(root.map(|post| post.tag_list()).unwrap_or_default()),
+    );
+    // 说明渲染失败时仍然发送，说明变量为空字符串，模板中的变量不会原样留下
+    let caption = crate::caption::render(id, img_data).unwrap_or_else(|e| {
+        log::error!("render caption of {} for webhook failed: {}", id, e);
+        Default::default()
+    });
+    vars.insert("caption".to_string(), json!(caption.text));
+    vars.insert("caption_markdown".to_string(), json!(caption.markdown));
+    vars.insert("caption_html".to_string(), json!(caption.html));
+    vars.insert("images".to_string(), json!(images));
+    vars.insert("posts".to_string(), json!(posts));
+    vars
+}
+
+/// 按变量展开 JSON 模板
+fn expand(template: &Value, vars: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            if let Some(value) = text
+                .strip_prefix('{')
+                .and_then(|text| text.strip_suffix('}'))
+                .and_then(|name| vars.get(name))
+            {
+                return value.clone();
+            }
+            let vars = vars
+                .iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        Value::String(text) => text.clone(),
+                        Value::Null => String::new(),
+                        value => value.to_string(),
+                    };
+                    (name.as_str(), value)
+                })
+                .collect();
+            Value::String(super::render(text, &vars))
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| expand(item, vars)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), expand(value, vars)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = Map::from_iter([
+            ("id".to_string(), json!(1)),
+            ("tags".to_string(), json!(["a", "b"])),
+        ]);
+        let template = json!({"text": "post {id}", "tags": "{tags}", "keep": 1});
+        assert_eq!(
+            expand(&template, &vars),
+            json!({"text": "post 1", "tags": ["a", "b"], "keep": 1})
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let args = |template: &str| WebhookArgs {
+            webhook_url: None,
+            webhook_mode: WebhookMode::Json,
+            webhook_template: Some(template.to_string()),
+            webhook_secret: None,
+            webhook_retries: 0,
+            webhook_timeout: 1,
+        };
+        assert!(args(r#"{"text": "new {url}", "tags": "{tags}"}"#)
+            .validate()
+            .is_ok());
+        assert!(args(r#"{"text": "{Literal braces}"}"#).validate().is_ok());
+        assert!(args(r#"{"text": "{urll}"}"#).validate().is_err());
+        assert!(args(r#"{"text": "#).validate().is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+
+use crate::yande::Post;
+
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// 生成包含标签、作者和来源的 XMP 数据包
+pub fn packet(post: &Post) -> String {
+    let subjects: String = post
+        .tag_list()
+        .iter()
+        .map(|tag| format!("<rdf:li>{}</rdf:li>", escape(tag)))
+        .collect();
+    let creators: String = post
+        .tags_of("artist")
+        .iter()
+        .map(|tag| format!("<rdf:li>{}</rdf:li>", escape(tag)))
+        .collect();
+
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+            "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+            "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">",
+            "<dc:identifier>{md5}</dc:identifier>",
+            "<dc:source>https://yande.re/post/show/{id}</dc:source>",
+            "<dc:creator><rdf:Seq>{creators}</rdf:Seq></dc:creator>",
+            "<dc:subject><rdf:Bag>{subjects}</rdf:Bag></dc:subject>",
+            "</rdf:Description>",
+            "</rdf:RDF>",
+            "</x:xmpmeta>",
+            "<?xpacket end=\"w\"?>"
+        ),
+        md5 = escape(&post.md5),
+        id = post.id,
+        creators = creators,
+        subjects = subjects,
+    )
+}
+
+/// 把 XMP 写入 JPEG（APP1）或 PNG（iTXt），其他格式返回错误
+pub fn embed(data: &[u8], packet: &str) -> Result<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        embed_jpeg(data, packet)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        embed_png(data, packet)
+    } else {
+        Err(anyhow::anyhow!("unsupported image format for xmp"))
+    }
+}
+
+fn embed_jpeg(data: &[u8], packet: &str) -> Result<Vec<u8>> {
+    let len = 2 + JPEG_XMP_HEADER.len() + packet.len();
+    let len = u16::try_from(len).map_err(|_| anyhow::anyhow!("xmp packet too large"))?;
+
+    // 放在 SOI 和 JFIF APP0 之后
+    let mut pos = 2;
+    if data.get(2..4) == Some(&[0xFF, 0xE0]) {
+        let app0 = data.get(4..6).ok_or(anyhow::anyhow!("truncated jpeg"))?;
+        pos += 2 + u16::from_be_bytes([app0[0], app0[1]]) as usize;
+    }
+    if pos > data.len() {
+        return Err(anyhow::anyhow!("truncated jpeg"));
+    }
+
+    let mut out = Vec::with_capacity(data.len() + len as usize + 2);
+    out.extend_from_slice(&data[..pos]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(JPEG_XMP_HEADER);
+    out.extend_from_slice(packet.as_bytes());
+    out.extend_from_slice(&data[pos..]);
+    Ok(out)
+}
+
+fn embed_png(data: &[u8], packet: &str) -> Result<Vec<u8>> {
+    // 放在 IHDR 之后
+    let ihdr_len = data.get(8..12).ok_or(anyhow::anyhow!("truncated png"))?;
+    let pos =
+        8 + 12 + u32::from_be_bytes([ihdr_len[0], ihdr_len[1], ihdr_len[2], ihdr_len[3]]) as usize;
+    if pos > data.len() {
+        return Err(anyhow::anyhow!("truncated png"));
+    }
+
+    let mut chunk = b"iTXt".to_vec();
+    chunk.extend_from_slice(b"XML:com.adobe.xmp\0");
+    // 不压缩，语言和翻译关键字为空
+    chunk.extend_from_slice(&[0, 0, 0, 0]);
+    chunk.extend_from_slice(packet.as_bytes());
+
+    let mut out = Vec::with_capacity(data.len() + chunk.len() + 8);
+    out.extend_from_slice(&data[..pos]);
+    out.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&crc32fast::hash(&chunk).to_be_bytes());
+    out.extend_from_slice(&data[pos..]);
+    Ok(out)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 依次取出 JPEG 的标记段，遇到 SOS 后停止
+    fn segments(data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut segments = Vec::new();
+        let mut pos = 2;
+        while data[pos] == 0xFF && data[pos + 1] != 0xDA {
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            segments.push((data[pos + 1], &data[pos + 4..pos + 2 + len]));
+            pos += 2 + len;
+        }
+        segments
+    }
+
+    #[test]
+    fn test_embed_jpeg() {
+        let mut jpeg = Vec::new();
+        image::DynamicImage::new_rgb8(8, 8)
+            .write_to(
+                &mut std::io::Cursor::new(&mut jpeg),
+                image::ImageOutputFormat::Jpeg(80),
+            )
+            .unwrap();
+        let post = Post {
+            id: 1,
+            tags: "foo <bar>".to_string(),
+            ..Default::default()
+        };
+        let packet = packet(&post);
+
+        let embedded = embed(&jpeg, &packet).unwrap();
+        let xmp: Vec<_> = segments(&embedded)
+            .into_iter()
+            .filter(|(marker, _)| *marker == 0xE1)
+            .filter_map(|(_, body)| body.strip_prefix(JPEG_XMP_HEADER))
+            .collect();
+        assert_eq!(xmp, [packet.as_bytes()]);
+        assert!(packet.contains("<rdf:li>&lt;bar&gt;</rdf:li>"));
+        image::load_from_memory(&embedded).unwrap();
+
+        assert!(embed(b"GIF89a", &packet).is_err());
+    }
+}
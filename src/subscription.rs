@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::{
+    bot, caption, control,
+    db::{Subscription, DB},
+    yande::{ImgData, DB_HANDLE},
+};
+
+fn db() -> &'static DB {
+    DB_HANDLE.get_or_init(DB::init)
+}
+
+pub fn subscribe(user: &str, tags: &[String], min_score: Option<u64>) -> Result<()> {
+    db().subscribe(&new(user, tags, min_score))
+}
+
+pub fn unsubscribe(user: &str, tags: &[String]) -> Result<bool> {
+    db().unsubscribe(&new(user, tags, None))
+}
+
+pub fn list(user: &str) -> Result<Vec<Subscription>> {
+    db().subscriptions(Some(user))
+}
+
+/// 标签排序后保存，顺序不同的同一组标签视为同一个订阅
+fn new(user: &str, tags: &[String], min_score: Option<u64>) -> Subscription {
+    let mut tags = tags.to_vec();
+    tags.sort();
+    tags.dedup();
+    Subscription {
+        user: user.to_string(),
+        tags,
+        min_score,
+    }
+}
+
+/// 家族中任意帖子带有的标签都算
+fn matches(subscription: &Subscription, score: u64, tags: &HashSet<&str>) -> bool {
+    score >= subscription.min_score.unwrap_or_else(control::threshold)
+        && subscription
+            .tags
+            .iter()
+            .all(|tag| tags.contains(tag.as_str()))
+}
+
+/// 检查候选帖子，私信通知订阅了它的用户，不受房间阈值和黑名单影响
+pub async fn notify(id: i64, img_data: &ImgData) {
+    let subscriptions = match db().subscriptions(None) {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            log::error!("read subscriptions failed: {}", e);
+            return;
+        }
+    };
+    let tags: HashSet<&str> = img_data
+        .posts
+        .values()
+        .flat_map(|post| post.tag_list())
+        .collect();
+
+    let mut notified = HashSet::new();
+    for subscription in subscriptions {
+        if notified.contains(&subscription.user) || !matches(&subscription, img_data.score, &tags) {
+            continue;
+        }
+        notified.insert(subscription.user.clone());
+        match db().mark_notified(&subscription.user, id) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                log::error!("mark notified failed: {}", e);
+                continue;
+            }
+        }
+
+        log::info!("notify {} of {}", subscription.user, id);
+        let result = match caption::render(id, img_data) {
+            Ok(caption) => bot::send_dm(&subscription.user, &caption).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::error!("notify {} failed: {}", subscription.user, e);
+            db().unmark_notified(&subscription.user, id)
+                .unwrap_or_else(|e| log::error!("unmark notified failed: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let subscription = new(
+            "@a:example.org",
+            &["b".to_string(), "a".to_string(), "a".to_string()],
+            Some(100),
+        );
+        assert_eq!(subscription.tags, vec!["a", "b"]);
+
+        let tags = HashSet::from(["a", "b", "c"]);
+        assert!(matches(&subscription, 100, &tags));
+        assert!(!matches(&subscription, 99, &tags));
+        assert!(!matches(&subscription, 200, &HashSet::from(["a", "c"])));
+    }
+}
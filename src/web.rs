@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+
+use axum::Router;
+
+use crate::{args, feed, health, metrics};
+
+#[derive(clap::Args, Debug)]
+pub struct WebArgs {
+    /// 内置 HTTP 服务的监听地址，例如 0.0.0.0:8080
+    /// 不设置则不启动
+    #[arg(long, env = "HTTP_ADDR")]
+    pub http_addr: Option<SocketAddr>,
+
+    /// 对外访问的地址，用于生成订阅中的链接
+    /// 默认为 http://{HTTP_ADDR}
+    #[arg(long, env = "PUBLIC_URL")]
+    pub public_url: Option<String>,
+}
+
+impl WebArgs {
+    /// 订阅中的链接必须是绝对地址
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        let Some(url) = &self.public_url else {
+            return Ok(());
+        };
+        match url::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+            _ => Err(clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                format!("PUBLIC_URL must be an absolute http(s) url, got {url}\n"),
+            )),
+        }
+    }
+}
+
+/// 没有设置 PUBLIC_URL 时用监听地址，不启动 HTTP 服务时不会生成订阅，只保证是绝对地址
+pub fn public_url() -> String {
+    let config = &args().web;
+    match (&config.public_url, config.http_addr) {
+        (Some(url), _) => url.trim_end_matches('/').to_string(),
+        (None, Some(addr)) => format!("http://{addr}"),
+        (None, None) => "http://localhost".to_string(),
+    }
+}
+
+pub async fn serve() {
+    let Some(addr) = args().web.http_addr else {
+        return;
+    };
+    if args().web.public_url.is_none() && addr.ip().is_unspecified() {
+        log::warn!("PUBLIC_URL is not set, links in feeds use http://{addr}");
+    }
+
+    let app = Router::new()
+        .merge(feed::router())
+        .merge(metrics::router())
+        .merge(health::router());
+    let app = app.merge(crate::bot::webhook_router());
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(builder) => builder,
+        Err(e) => {
+            log::error!("http server bind {} failed: {}", addr, e);
+            return;
+        }
+    };
+    log::info!("http server listening on {}", addr);
+    let server = server
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async move {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to install CTRL+C handler")
+        });
+
+    server
+        .await
+        .unwrap_or_else(|e| log::error!("http server failed: {}", e));
+}
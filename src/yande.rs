@@ -1,11 +1,12 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
     io::Write,
     path::PathBuf,
     sync::OnceLock,
 };
 
-use crate::{args, db::DB};
+use crate::{args, control, db::DB, feedback::Feedback, metrics::metrics, subscription};
 use anyhow::Result;
 use reqwest::{header, Client, ClientBuilder};
 use select::{
@@ -16,11 +17,87 @@ use select::{
 pub static CLIENT: OnceLock<Client> = OnceLock::new();
 pub static DB_HANDLE: OnceLock<DB> = OnceLock::new();
 
-type ImgInfo = HashMap<i64, ImgData>;
+#[derive(clap::Args, Debug)]
+pub struct SourceArgs {
+    /// 扫描的热门列表页面，逗号分隔
+    #[arg(
+        long = "source",
+        env = "SOURCES",
+        value_delimiter = ',',
+        default_value = "https://yande.re/post/popular_recent,https://yande.re/post/popular_recent?period=1w"
+    )]
+    pub sources: Vec<String>,
+}
+
+type ImgInfo = Vec<(i64, ImgData)>;
 #[derive(Debug, Clone)]
 pub struct ImgData {
     pub score: u64,
     pub url: VecDeque<(i64, String)>,
+    pub posts: HashMap<i64, Post>,
+}
+
+/// 页面中 `Post.register_resp(...)` 携带的帖子信息
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Post {
+    pub id: i64,
+    #[serde(default)]
+    pub tags: String,
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub score: i64,
+    #[serde(default)]
+    pub md5: String,
+    #[serde(default)]
+    pub file_size: u64,
+    #[serde(default)]
+    pub file_ext: String,
+    #[serde(default)]
+    pub file_url: String,
+    #[serde(default)]
+    pub preview_url: String,
+    #[serde(default)]
+    pub sample_url: String,
+    #[serde(default)]
+    pub rating: String,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    /// 标签 -> 类型（artist、copyright、character、general 等）
+    #[serde(default)]
+    pub tag_types: BTreeMap<String, String>,
+}
+
+impl Post {
+    pub fn tag_list(&self) -> Vec<&str> {
+        self.tags.split_whitespace().collect()
+    }
+
+    pub fn tags_of(&self, kind: &str) -> Vec<&str> {
+        self.tag_list()
+            .into_iter()
+            .filter(|tag| self.tag_types.get(*tag).is_some_and(|t| t == kind))
+            .collect()
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.tags_of("artist").first().copied()
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RegisterResp {
+    posts: Vec<Post>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
 }
 
 fn client_builder() -> Client {
@@ -37,12 +114,28 @@ fn client_builder() -> Client {
         .unwrap()
 }
 
-pub async fn get(url: &str) -> Result<String> {
+/// 发送请求并按状态码计数
+async fn send(url: &str) -> Result<reqwest::Response> {
     let client = CLIENT.get_or_init(client_builder);
-    let resp = client.get(url).send().await?.text().await?;
+    let result = client.get(url).send().await;
+    let status = match &result {
+        Ok(resp) => resp.status().as_str().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    metrics().http_requests.with_label_values(&[&status]).inc();
+    Ok(result?)
+}
+
+pub async fn get(url: &str) -> Result<String> {
+    let resp = send(url).await?.text().await?;
     Ok(resp)
 }
 
+pub async fn get_bytes(url: &str) -> Result<Vec<u8>> {
+    let resp = send(url).await?.error_for_status()?;
+    Ok(resp.bytes().await?.to_vec())
+}
+
 pub fn get_image_list(html: &str) -> Result<Vec<i64>> {
     let document = document::Document::from(html);
 
@@ -63,72 +156,98 @@ pub fn get_image_list(html: &str) -> Result<Vec<i64>> {
     Ok(image_list)
 }
 
-async fn find_parent(id: i64) -> Result<(i64, Document)> {
+async fn find_parent(id: i64) -> Result<(i64, String)> {
     let url = format!("https://yande.re/post/show/{}", id);
     let html = get(&url).await?;
 
-    let document = document::Document::from(html.as_str());
-
-    let result = match document.find(Class("status-notice")).find(|node| {
-        node.children()
-            .any(|node| node.is(Name("a")) && node.text().contains("parent post"))
-    }) {
-        Some(node) => {
-            let id = node
-                .find(Name("a"))
-                .find(|node| {
-                    node.attr("href")
-                        .is_some_and(|href| href.starts_with("/post/show/"))
-                })
-                .ok_or(anyhow::anyhow!("not found parent post"))?
-                .attr("href")
-                .ok_or(anyhow::anyhow!("not found href"))?
-                .trim()
-                .replace("/post/show/", "")
-                .parse::<i64>()?;
-            let url = format!("https://yande.re/post/show/{}", id);
-            let html = get(&url).await?;
+    // Document 不是 Send，不能跨 await 持有
+    let parent = {
+        let document = document::Document::from(html.as_str());
+        match document.find(Class("status-notice")).find(|node| {
+            node.children()
+                .any(|node| node.is(Name("a")) && node.text().contains("parent post"))
+        }) {
+            Some(node) => Some(
+                node.find(Name("a"))
+                    .find(|node| {
+                        node.attr("href")
+                            .is_some_and(|href| href.starts_with("/post/show/"))
+                    })
+                    .ok_or(anyhow::anyhow!("not found parent post"))?
+                    .attr("href")
+                    .ok_or(anyhow::anyhow!("not found href"))?
+                    .trim()
+                    .replace("/post/show/", "")
+                    .parse::<i64>()?,
+            ),
+            None => None,
+        }
+    };
 
-            (id, document::Document::from(html.as_str()))
+    let result = match parent {
+        Some(id) => {
+            let url = format!("https://yande.re/post/show/{}", id);
+            (id, get(&url).await?)
         }
-        None => (id, document),
+        None => (id, html),
     };
 
     Ok(result)
 }
 
 pub async fn get_image_info(id: i64) -> Result<(i64, ImgData)> {
-    let (id, document) = find_parent(id).await?;
+    let (id, html) = find_parent(id).await?;
 
     let mut download_link: VecDeque<(i64, String)> = VecDeque::new();
 
-    let mut score = find_score(id, &document)?;
+    let mut posts = HashMap::new();
 
-    download_link.push_back((id, find_raw_url(&document)?));
+    // Document 不是 Send，不能跨 await 持有
+    let (mut score, children) = {
+        let document = document::Document::from(html.as_str());
+        let score = find_score(id, &document)?;
 
-    let child = document.find(Class("status-notice")).find(|node| {
-        node.children()
-            .any(|node| node.is(Name("a")) && node.text().contains("child post"))
-    });
+        download_link.push_back((id, find_raw_url(&document)?));
+        match find_post(id, &document) {
+            Ok(post) => {
+                posts.insert(id, post);
+            }
+            Err(e) => log::warn!("get post {id} metadata failed: {}", e),
+        }
 
-    if let Some(node) = child {
-        for node in node.children().filter(|node| {
-            node.is(Name("a"))
-                && node
-                    .attr("href")
-                    .is_some_and(|href| href.starts_with("/post/show/"))
-        }) {
-            let id = node.text().trim().parse::<i64>()?;
+        let mut children = Vec::new();
+        let child = document.find(Class("status-notice")).find(|node| {
+            node.children()
+                .any(|node| node.is(Name("a")) && node.text().contains("child post"))
+        });
+        if let Some(node) = child {
+            for node in node.children().filter(|node| {
+                node.is(Name("a"))
+                    && node
+                        .attr("href")
+                        .is_some_and(|href| href.starts_with("/post/show/"))
+            }) {
+                children.push(node.text().trim().parse::<i64>()?);
+            }
+        }
+        (score, children)
+    };
 
-            let html = get(&format!("https://yande.re/post/show/{}", id)).await?;
-            let document = document::Document::from(html.as_str());
+    for id in children {
+        let html = get(&format!("https://yande.re/post/show/{}", id)).await?;
+        let document = document::Document::from(html.as_str());
 
-            let score_child = find_score(id, &document)?;
-            if score_child > score {
-                score = score_child;
-            }
+        let score_child = find_score(id, &document)?;
+        if score_child > score {
+            score = score_child;
+        }
 
-            download_link.push_back((id, find_raw_url(&document)?));
+        download_link.push_back((id, find_raw_url(&document)?));
+        match find_post(id, &document) {
+            Ok(post) => {
+                posts.insert(id, post);
+            }
+            Err(e) => log::warn!("get post {id} metadata failed: {}", e),
         }
     }
 
@@ -137,6 +256,7 @@ pub async fn get_image_info(id: i64) -> Result<(i64, ImgData)> {
         ImgData {
             score,
             url: download_link,
+            posts,
         },
     ))
 }
@@ -152,6 +272,35 @@ fn find_score(id: i64, document: &Document) -> Result<u64> {
     Ok(score)
 }
 
+fn find_post(id: i64, document: &Document) -> Result<Post> {
+    let script = document
+        .find(Name("script"))
+        .map(|node| node.text())
+        .find(|text| text.contains("Post.register_resp("))
+        .ok_or(anyhow::anyhow!("not found Post.register_resp"))?;
+    let (_, json) = script
+        .split_once("Post.register_resp(")
+        .ok_or(anyhow::anyhow!("not found Post.register_resp"))?;
+
+    // 只解析第一个 JSON 值，忽略后面的 `);`
+    let resp: RegisterResp = serde_json::Deserializer::from_str(json)
+        .into_iter()
+        .next()
+        .ok_or(anyhow::anyhow!("empty Post.register_resp"))??;
+
+    let mut post = resp
+        .posts
+        .into_iter()
+        .find(|post| post.id == id)
+        .ok_or(anyhow::anyhow!("not found post {id} in Post.register_resp"))?;
+    post.tag_types = post
+        .tag_list()
+        .into_iter()
+        .filter_map(|tag| Some((tag.to_string(), resp.tags.get(tag)?.clone())))
+        .collect();
+    Ok(post)
+}
+
 fn find_raw_url(document: &Document) -> Result<String> {
     let url = document
         .find(Name("a"))
@@ -162,47 +311,178 @@ fn find_raw_url(document: &Document) -> Result<String> {
     Ok(url.to_string())
 }
 
-pub async fn get_download_list(image_list: Vec<i64>) -> Result<ImgInfo> {
-    let mut download_list = HashMap::new();
-
-    for img_id in image_list {
-        if DB_HANDLE
-            .get_or_init(DB::init)
-            .contains(&img_id.to_string())?
-        {
-            log::debug!("{} is exists,skip", img_id);
-            continue;
+/// 取出所有热门列表中的帖子，全部失败时返回错误
+pub async fn fetch_sources() -> Result<Vec<i64>> {
+    let mut image_list = Vec::new();
+    let mut failed = 0;
+    for source in args().source.sources.iter() {
+        let result = match get(source).await {
+            Ok(resp) => get_image_list(&resp),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(list) => image_list.extend(list),
+            Err(e) => {
+                log::error!("fetch {} failed: {}", source, e);
+                failed += 1;
+            }
         }
+    }
+    if failed == args().source.sources.len() {
+        anyhow::bail!("all sources failed");
+    }
+    Ok(image_list)
+}
 
-        let (id, img_data) = match get_image_info(img_id).await {
-            Ok(img_data) => img_data,
-            Err(e) => {
-                log::error!("get image info failed: {}", e);
+/// 被过滤的帖子，`reason` 与指标中的原因相同
+#[derive(Debug, serde::Serialize)]
+pub struct Skipped {
+    pub id: i64,
+    pub reason: &'static str,
+    pub detail: String,
+}
+
+pub async fn get_download_list(image_list: Vec<i64>) -> Result<ImgInfo> {
+    let (download_list, _) = select_posts(image_list, false).await?;
+    Ok(download_list)
+}
+
+/// 一次扫描的过滤条件，`dry_run` 时不通知订阅，也不把帖子记为已扫描
+struct Filter<'a> {
+    db: &'a DB,
+    threshold: u64,
+    blacklist: Vec<String>,
+    feedback: Feedback,
+    dry_run: bool,
+}
+
+/// 过滤并解析家族，按加权后的评分排序
+/// `dry_run` 时不通知订阅，也不把帖子记为已扫描
+pub async fn select_posts(image_list: Vec<i64>, dry_run: bool) -> Result<(ImgInfo, Vec<Skipped>)> {
+    let mut blacklist = control::blacklist();
+    blacklist.extend(args().control.blacklist.iter().cloned());
+    let filter = Filter {
+        db: DB_HANDLE.get_or_init(DB::init),
+        threshold: control::threshold(),
+        blacklist,
+        feedback: Feedback::load(),
+        dry_run,
+    };
+    filter.select(image_list, get_image_info).await
+}
+
+impl Filter<'_> {
+    /// `get_info` 取得帖子所在的家族
+    async fn select<F, Fut>(
+        &self,
+        image_list: Vec<i64>,
+        get_info: F,
+    ) -> Result<(ImgInfo, Vec<Skipped>)>
+    where
+        F: Fn(i64) -> Fut,
+        Fut: Future<Output = Result<(i64, ImgData)>>,
+    {
+        let mut download_list = Vec::new();
+        let mut skipped = Vec::new();
+        // 同一次扫描中已经选中的家族，预览时不写数据库也不会重复
+        let mut selected = HashSet::new();
+        let metrics = metrics();
+        metrics.posts_fetched.inc_by(image_list.len() as u64);
+        let mut filtered = |id: i64, reason: &'static str, detail: String| {
+            metrics.posts_filtered.with_label_values(&[reason]).inc();
+            skipped.push(Skipped { id, reason, detail });
+        };
+
+        for img_id in image_list {
+            if selected.contains(&img_id) || self.db.contains(&img_id.to_string())? {
+                log::debug!("{} is exists,skip", img_id);
+                filtered(img_id, "exists", "already seen".to_string());
                 continue;
             }
-        };
-        log::debug!("get image info: {}", img_id);
-        if img_data.score < 50 || DB_HANDLE.get_or_init(DB::init).contains(&id.to_string())? {
-            log::debug!("{} score ,skip", img_id);
-            continue;
-        }
 
-        download_list.insert(id, img_data.clone());
-        for (id, _) in img_data.url.iter() {
-            DB_HANDLE.get_or_init(DB::init).insert(&id.to_string())?;
+            let (id, img_data) = match get_info(img_id).await {
+                Ok(img_data) => img_data,
+                Err(e) => {
+                    log::error!("get image info failed: {}", e);
+                    filtered(img_id, "info_error", e.to_string());
+                    continue;
+                }
+            };
+            log::debug!("get image info: {}", img_id);
+            if !self.dry_run {
+                subscription::notify(id, &img_data).await;
+            }
+            // 房间喜欢的标签更容易达到阈值，也排在前面发送
+            let factor = img_data
+                .posts
+                .get(&id)
+                .map(|post| self.feedback.factor(post.tag_list()))
+                .unwrap_or(1.0);
+            let score = img_data.score as f64 * factor;
+            if score < self.threshold as f64 {
+                log::debug!("{} score ,skip", img_id);
+                filtered(
+                    img_id,
+                    "score",
+                    format!("score {:.0} (x{:.2}) < {}", score, factor, self.threshold),
+                );
+                continue;
+            }
+            if selected.contains(&id) || self.db.contains(&id.to_string())? {
+                log::debug!("parent {} of {} is exists, skip", id, img_id);
+                filtered(img_id, "exists", format!("parent {} already seen", id));
+                continue;
+            }
+            if let Some(tag) = img_data
+                .posts
+                .values()
+                .flat_map(|post| post.tag_list())
+                .find(|tag| self.blacklist.iter().any(|t| t == tag))
+            {
+                log::debug!("{} has blacklisted tag {}, skip", img_id, tag);
+                filtered(img_id, "blacklist", format!("blacklisted tag {}", tag));
+                // 黑名单不会随时间变化，记为已扫描，之后不再获取和通知订阅
+                mark_seen(self.db, &mut selected, &img_data, self.dry_run)?;
+                continue;
+            }
+
+            mark_seen(self.db, &mut selected, &img_data, self.dry_run)?;
+            download_list.push((score, id, img_data));
         }
+
+        download_list.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok((
+            download_list
+                .into_iter()
+                .map(|(_, id, img_data)| (id, img_data))
+                .collect(),
+            skipped,
+        ))
     }
+}
 
-    Ok(download_list)
+/// 把整个家族记为已扫描，预览时只记在本次扫描中
+fn mark_seen(
+    db: &DB,
+    selected: &mut HashSet<i64>,
+    img_data: &ImgData,
+    dry_run: bool,
+) -> Result<()> {
+    for (id, _) in img_data.url.iter() {
+        selected.insert(*id);
+        if !dry_run {
+            db.insert(&id.to_string())?;
+        }
+    }
+    Ok(())
 }
 
 pub async fn download_img((id, url): (i64, &str)) -> Result<PathBuf> {
-    let client = CLIENT.get_or_init(client_builder);
+    let resp = send(url).await?;
 
-    let resp = client.get(url).send().await?;
-
-    let ext = url.split('.').last().unwrap_or("jpg");
+    let ext = url.rsplit('.').next().unwrap_or("jpg");
     let bytes = resp.bytes().await?;
+    metrics().download_bytes.inc_by(bytes.len() as u64);
 
     let path = PathBuf::from(format!("{}/tmp/{}.{}", &args().data_dir, id, ext));
 
@@ -231,6 +511,74 @@ mod tests {
         assert!(!image_list.is_empty());
     }
 
+    #[test]
+    fn test_find_post() {
+        let html = r#"<html><body><script type="text/javascript">
+            Post.register_resp({"posts":[{"id":1,"tags":"foo bar_(artist)","rating":"s","score":80,"file_ext":"png"}],"tags":{"foo":"general","bar_(artist)":"artist"},"votes":{}});
+        </script></body></html>"#;
+        let document = Document::from(html);
+        let post = find_post(1, &document).unwrap();
+        assert_eq!(post.rating, "s");
+        assert_eq!(post.artist(), Some("bar_(artist)"));
+        assert_eq!(post.tags_of("general"), vec!["foo"]);
+    }
+
+    #[tokio::test]
+    async fn test_select_dry_run() {
+        let path = std::env::temp_dir().join(format!("select_test_{}", std::process::id()));
+        let db = DB::open_path(&path).unwrap();
+        db.insert("1").unwrap();
+        let subscription = crate::db::Subscription {
+            user: "@user:example.org".to_string(),
+            tags: vec!["foo".to_string()],
+            min_score: None,
+        };
+        db.subscribe(&subscription).unwrap();
+        let before = db.stats().unwrap();
+
+        // 1 已扫描，2 分数足够，3 分数不够，4 在黑名单中
+        let info = |id: i64| async move {
+            let tags = if id == 4 { "foo bad" } else { "foo" };
+            let post = Post {
+                id,
+                tags: tags.to_string(),
+                ..Default::default()
+            };
+            Ok((
+                id,
+                ImgData {
+                    score: if id == 3 { 10 } else { 100 },
+                    url: VecDeque::from([(id, format!("https://example.org/{id}.jpg"))]),
+                    posts: HashMap::from([(id, post)]),
+                },
+            ))
+        };
+        let filter = Filter {
+            db: &db,
+            threshold: 50,
+            blacklist: vec!["bad".to_string()],
+            feedback: Feedback::default(),
+            dry_run: true,
+        };
+        let (list, skipped) = filter.select(vec![1, 2, 3, 4, 2], info).await.unwrap();
+
+        let ids: Vec<_> = list.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![2]);
+        let reasons: Vec<_> = skipped.iter().map(|s| (s.id, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            vec![(1, "exists"), (3, "score"), (4, "blacklist"), (2, "exists")]
+        );
+        for id in ["2", "3", "4"] {
+            assert!(!db.contains(id).unwrap());
+        }
+        assert_eq!(db.stats().unwrap(), before);
+        assert_eq!(db.subscriptions(None).unwrap(), vec![subscription]);
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
     #[tokio::test]
     async fn test_get_image_info() {
         let image_info = get_image_info(1124159).await.unwrap();